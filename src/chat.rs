@@ -0,0 +1,193 @@
+use crate::config::{Profile, Selection};
+use crate::history;
+use crate::ledger;
+use crate::output::{self, Delivery, OutputFormat};
+use crate::pricing::Pricing;
+use crate::toolbox::{self, Toolbox};
+use anyhow::Result;
+use clm::providers::{self, AiProvider, ChatMessage, ChatRequest, ProviderConfig};
+use std::io::Write;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+const HELP: &str = "Commands:
+  /reset             Clear the conversation history
+  /model [name]      Show or switch the model
+  /provider [name]   Show or switch the provider (resets the model to its default)
+  /save <path>       Save the conversation to a JSON file
+  /help              Show this help
+  /exit              Leave the chat";
+
+struct Session {
+    profile: Profile,
+    /// The provider in use, followed by its fallbacks.
+    chain: Vec<ProviderConfig>,
+    provider: Box<dyn AiProvider + Send + Sync>,
+    /// System prompt, options and tools shared by every turn.
+    request: ChatRequest,
+    toolbox: Toolbox,
+    history: Vec<ChatMessage>,
+}
+
+impl Session {
+    fn config(&self) -> &ProviderConfig {
+        &self.chain[0]
+    }
+
+    /// Replaces the provider in use, keeping the fallbacks.
+    fn switch(&mut self, config: ProviderConfig) -> Result<()> {
+        let chain: Vec<ProviderConfig> = std::iter::once(config.clone())
+            .chain(
+                self.chain[1..]
+                    .iter()
+                    .filter(|c| c.label() != config.label())
+                    .cloned(),
+            )
+            .collect();
+
+        self.provider = providers::get_provider_chain(&chain)?;
+        self.chain = chain;
+        Ok(())
+    }
+
+    /// Handles a slash command. Returns `false` when the session should end.
+    fn command(&mut self, line: &str) -> Result<bool> {
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+
+        match command {
+            "/exit" | "/quit" => return Ok(false),
+            "/help" => println!("{}", HELP),
+            "/reset" => {
+                self.history.clear();
+                println!("Conversation cleared.");
+            }
+            "/model" if arg.is_empty() => println!(
+                "Model: {}",
+                self.config().model.as_deref().unwrap_or("provider default")
+            ),
+            "/model" => {
+                self.switch(ProviderConfig {
+                    model: Some(arg.to_string()),
+                    ..self.config().clone()
+                })?;
+                println!("Switched to model {}.", arg);
+            }
+            "/provider" if arg.is_empty() => println!("Provider: {}", self.config().provider),
+            "/provider" => {
+                let selection = Selection {
+                    provider: Some(arg.to_string()),
+                    model: None,
+                    max_retries: self.config().max_retries,
+                    timeouts: self.config().timeouts,
+                };
+                self.switch(self.profile.provider_config(&selection)?)?;
+                println!("Switched to provider {}.", self.config().provider);
+            }
+            "/save" if arg.is_empty() => anyhow::bail!("Usage: /save <path>"),
+            "/save" => {
+                std::fs::write(arg, serde_json::to_string_pretty(&self.history)?)?;
+                println!("Saved {} messages to {}.", self.history.len(), arg);
+            }
+            _ => anyhow::bail!("Unknown command: {} (try /help)", command),
+        }
+
+        Ok(true)
+    }
+}
+
+pub async fn run(
+    profile: Profile,
+    chain: Vec<ProviderConfig>,
+    request: ChatRequest,
+    toolbox: Toolbox,
+    pricing: &Pricing,
+    delivery: Delivery,
+) -> Result<()> {
+    let delivery = Delivery {
+        format: OutputFormat::Text,
+        ..delivery
+    };
+    let mut session = Session {
+        provider: providers::get_provider_chain(&chain)?,
+        profile,
+        chain,
+        request,
+        toolbox,
+        history: Vec::new(),
+    };
+
+    println!(
+        "clm chat ({}) - type /help for commands, /exit to quit",
+        session.config().provider
+    );
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+
+        let Some(line) = lines.next_line().await? else {
+            println!();
+            break;
+        };
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('/') {
+            match session.command(line) {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    continue;
+                }
+            }
+        }
+
+        session.history.push(ChatMessage::user(line));
+
+        let request = ChatRequest {
+            messages: session.history.clone(),
+            ..session.request.clone()
+        };
+        let mut conversation = request.clone();
+
+        match toolbox::complete(
+            session.provider.as_ref(),
+            &mut conversation,
+            &session.toolbox,
+            delivery,
+        )
+        .await
+        {
+            Ok(response) => {
+                let cost = pricing.cost(&response);
+                output::print_summary(OutputFormat::Text, &response, cost)?;
+                ledger::record(&response, cost);
+                history::record(
+                    &session.chain[response.failures.len()].provider,
+                    &request,
+                    &response,
+                );
+                // Keep the tool turns so later questions can refer to them.
+                session.history = conversation.messages;
+                session
+                    .history
+                    .push(ChatMessage::assistant(response.content));
+            }
+            Err(e) => {
+                // Drop the unanswered turn so the user can simply retry.
+                session.history.pop();
+                eprintln!("Error: {}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use crate::config::{self, Profile, Selection};
+use crate::history;
+use crate::input;
+use crate::ledger;
+use crate::output;
+use crate::pricing::{Pricing, format_cost};
+use anyhow::Result;
+use clm::providers::{self, AiResponse, ChatRequest};
+
+/// Sends `request` to every model in `specs` (`provider` or `provider:model`)
+/// at once. Answers are printed in the order given, each with its footer,
+/// followed by a summary table. A failing model does not stop the others.
+pub async fn run(
+    profile: &Profile,
+    specs: &[String],
+    settings: &Selection,
+    request: &ChatRequest,
+    pricing: &Pricing,
+) -> Result<()> {
+    if specs.len() < 2 {
+        anyhow::bail!(
+            "Give at least two models to compare, e.g. -m openai:gpt-4.1-mini -m anthropic:claude-sonnet-4"
+        );
+    }
+
+    let tasks: Vec<_> = specs
+        .iter()
+        .map(|spec| {
+            let (provider, model) = config::parse_spec(spec);
+            let config = profile.provider_config(&Selection {
+                provider: Some(provider),
+                model,
+                ..settings.clone()
+            });
+            let request = request.clone();
+
+            tokio::spawn(async move {
+                let config = config?;
+                let response = providers::get_provider(&config)?
+                    .query(&request)
+                    .await
+                    .map_err(|e| input::image_hint(e.into(), &request))?;
+                Ok::<_, anyhow::Error>((config.provider, response))
+            })
+        })
+        .collect();
+
+    let mut results = Vec::new();
+
+    for (spec, task) in specs.iter().zip(tasks) {
+        let result = match task.await {
+            Ok(result) => result,
+            Err(e) => Err(e.into()),
+        };
+
+        println!("=== {} ===", spec);
+        let result = match result {
+            Ok((provider, response)) => {
+                output::print_answer(&response);
+                output::print_warnings(&response);
+                output::warn_incomplete(&response);
+                let cost = pricing.cost(&response);
+                output::print_footer(&response, cost);
+                ledger::record(&response, cost);
+                history::record(&provider, request, &response);
+                Ok((response, cost))
+            }
+            Err(e) => {
+                println!("Error: {:#}", e);
+                Err(e)
+            }
+        };
+        println!();
+
+        results.push((spec.as_str(), result));
+    }
+
+    print_summary(&results);
+
+    if results.iter().all(|(_, result)| result.is_err()) {
+        anyhow::bail!("All models failed");
+    }
+
+    Ok(())
+}
+
+type Outcome = Result<(AiResponse, Option<f64>)>;
+
+fn print_summary(results: &[(&str, Outcome)]) {
+    let width = results
+        .iter()
+        .map(|(spec, _)| spec.len())
+        .chain(["Model".len()])
+        .max()
+        .unwrap_or(0);
+
+    println!(
+        "{:<width$}  {:>8}  {:>8}  {:>8}  {:>10}",
+        "Model", "Latency", "Input", "Output", "Cost"
+    );
+
+    for (spec, result) in results {
+        let Ok((response, cost)) = result else {
+            println!("{:<width$}  {:>8}", spec, "failed");
+            continue;
+        };
+
+        let count = |tokens: Option<u32>| tokens.map_or("-".to_string(), |t| t.to_string());
+        println!(
+            "{:<width$}  {:>8}  {:>8}  {:>8}  {:>10}",
+            spec,
+            format!("{:.2}s", response.duration.as_secs_f64()),
+            count(response.usage.input_tokens),
+            count(response.usage.output_tokens),
+            cost.map_or("N/A".to_string(), format_cost)
+        );
+    }
+}
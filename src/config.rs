@@ -0,0 +1,529 @@
+use crate::pricing::Price;
+use anyhow::Result;
+use clm::Setting;
+use clm::providers::http::Timeouts;
+use clm::providers::{GenerationOptions, ProviderConfig};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The provider used when neither the command line, the environment nor the
+/// profile names one.
+pub const DEFAULT_PROVIDER: &str = "google";
+
+/// Contents of `config.toml`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Price overrides keyed by `provider:model`, in USD per million tokens.
+    #[serde(default)]
+    pub pricing: BTreeMap<String, Price>,
+}
+
+/// A named set of settings, selected with `--profile` or `CLM_PROFILE`.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    /// Shell command whose output is used as the API key, e.g. `pass show openai`.
+    pub api_key_command: Option<String>,
+    /// Display name for the custom provider.
+    pub name: Option<String>,
+    /// Whether the custom provider's server reports token usage in streamed
+    /// answers when asked with `stream_options`.
+    pub stream_usage: Option<bool>,
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<u32>,
+    /// Upper bound on the tokens in an answer.
+    pub max_tokens: Option<u32>,
+    /// Sequences that end the answer when generated.
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<u64>,
+    /// Number of alternative answers to ask for.
+    pub n: Option<u32>,
+    /// Thinking budget for reasoning models: a token count or low, medium or
+    /// high.
+    pub think: Option<String>,
+    /// Follow-up requests allowed to continue an answer cut off at the token
+    /// limit.
+    pub auto_continue: Option<u32>,
+    pub max_retries: Option<u32>,
+    /// Timeouts in seconds, `0` to disable.
+    pub connect_timeout: Option<f64>,
+    pub read_timeout: Option<f64>,
+    pub timeout: Option<f64>,
+    pub stream_idle_timeout: Option<f64>,
+    /// Providers to try in order when the selected one fails, as `provider`
+    /// or `provider:model`.
+    pub fallback: Option<Vec<String>>,
+    /// Built-in tools the model may call.
+    pub tools: Option<Vec<String>>,
+    /// Bytes of `--file` attachments above which a warning is printed.
+    pub file_budget: Option<u64>,
+}
+
+/// Settings chosen explicitly, e.g. with `--provider`/`--model`. Unset fields
+/// fall back to the environment and the profile.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub max_retries: Option<u32>,
+    pub timeouts: Timeouts,
+}
+
+impl Config {
+    /// `$CLM_CONFIG`, else `$XDG_CONFIG_HOME/clm/config.toml`, else
+    /// `~/.config/clm/config.toml`.
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env("CLM_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+
+        let config_dir = env("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+        Some(config_dir.join("clm").join("config.toml"))
+    }
+
+    /// Loads the config file. A missing file is the same as an empty one.
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| invalid(format!("Invalid config file {}: {}", path.display(), e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(invalid(format!(
+                "Failed to read config file {}: {}",
+                path.display(),
+                e
+            ))),
+        }
+    }
+
+    /// Picks the profile given on the command line, then `CLM_PROFILE`, then
+    /// `default_profile`. Without any of them an empty profile is used.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        let Some(name) = name
+            .map(str::to_string)
+            .or_else(|| env("CLM_PROFILE"))
+            .or_else(|| self.default_profile.clone())
+        else {
+            return Ok(Profile::default());
+        };
+
+        self.profiles.get(&name).cloned().ok_or_else(|| {
+            let available: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            invalid(format!(
+                "Unknown profile '{}' (available: {})",
+                name,
+                if available.is_empty() {
+                    "none".to_string()
+                } else {
+                    available.join(", ")
+                }
+            ))
+        })
+    }
+}
+
+impl Profile {
+    /// Resolves the provider and model to use. The selection overrides
+    /// `CLM_PROVIDER`, `CLM_MODEL`, `CLM_MAX_RETRIES` and the `CLM_*_TIMEOUT`
+    /// variables, which override the profile. Selecting a
+    /// different provider without a model uses that provider's default model.
+    pub fn provider_config(&self, selection: &Selection) -> Result<ProviderConfig> {
+        let (provider, model) = self.resolve(selection, env("CLM_PROVIDER"), env("CLM_MODEL"));
+
+        let max_retries = match selection.max_retries {
+            Some(max_retries) => Some(max_retries),
+            None => parse_env("CLM_MAX_RETRIES")?.or(self.max_retries),
+        };
+
+        let timeouts = selection
+            .timeouts
+            .or(Timeouts {
+                connect: parse_env("CLM_CONNECT_TIMEOUT")?,
+                read: parse_env("CLM_READ_TIMEOUT")?,
+                total: parse_env("CLM_TIMEOUT")?,
+                stream_idle: parse_env("CLM_STREAM_IDLE_TIMEOUT")?,
+            })
+            .or(Timeouts {
+                connect: self.connect_timeout,
+                read: self.read_timeout,
+                total: self.timeout,
+                stream_idle: self.stream_idle_timeout,
+            });
+
+        Ok(ProviderConfig {
+            max_retries,
+            timeouts,
+            ..provider_config(self, &provider, model)?
+        })
+    }
+
+    /// The provider and model from the selection, else from `CLM_PROVIDER` and
+    /// `CLM_MODEL`, else from the profile. The profile's model only goes with
+    /// the profile's provider.
+    fn resolve(
+        &self,
+        selection: &Selection,
+        env_provider: Option<String>,
+        env_model: Option<String>,
+    ) -> (String, Option<String>) {
+        let profile_provider = self.provider.as_deref().unwrap_or(DEFAULT_PROVIDER);
+        let default_provider = env_provider.unwrap_or_else(|| profile_provider.to_string());
+        let profile_model = self
+            .model
+            .clone()
+            .filter(|_| default_provider.eq_ignore_ascii_case(profile_provider));
+        let default_model = env_model.or(profile_model);
+
+        match &selection.provider {
+            Some(provider) if !provider.eq_ignore_ascii_case(&default_provider) => {
+                (provider.clone(), selection.model.clone())
+            }
+            _ => (default_provider, selection.model.clone().or(default_model)),
+        }
+    }
+
+    /// The selected provider followed by its fallbacks from `CLM_FALLBACK`
+    /// (comma-separated, empty to disable) or the profile. Fallbacks share the
+    /// selection's retry and timeout settings; duplicates are dropped.
+    pub fn provider_chain(&self, selection: &Selection) -> Result<Vec<ProviderConfig>> {
+        let mut chain = vec![self.provider_config(selection)?];
+
+        let fallback = match std::env::var("CLM_FALLBACK") {
+            Ok(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(_) => self.fallback.clone().unwrap_or_default(),
+        };
+
+        for entry in fallback {
+            let (provider, model) = parse_spec(&entry);
+
+            let config = self.provider_config(&Selection {
+                provider: Some(provider),
+                model,
+                ..selection.clone()
+            })?;
+
+            if !chain.iter().any(|c| c.label() == config.label()) {
+                chain.push(config);
+            }
+        }
+
+        Ok(chain)
+    }
+
+    /// `CLM_SYSTEM_PROMPT` overrides the profile's system prompt. An empty
+    /// value is kept, since it disables the system prompt.
+    pub fn system_prompt(&self) -> Option<String> {
+        std::env::var("CLM_SYSTEM_PROMPT")
+            .ok()
+            .or_else(|| self.system_prompt.clone())
+    }
+
+    /// `CLM_FILE_BUDGET` overrides the profile's file budget.
+    pub fn file_budget(&self) -> Result<Option<u64>> {
+        Ok(parse_env("CLM_FILE_BUDGET")?.or(self.file_budget))
+    }
+
+    /// Generation options from `CLM_TEMPERATURE`, `CLM_TOP_P`, `CLM_TOP_K`,
+    /// `CLM_MAX_TOKENS`, `CLM_STOP` (comma-separated), `CLM_SEED`, `CLM_N` and
+    /// `CLM_THINK`, falling back to the profile.
+    pub fn generation_options(&self) -> Result<GenerationOptions> {
+        let env_options = GenerationOptions {
+            temperature: parse_env("CLM_TEMPERATURE")?,
+            top_p: parse_env("CLM_TOP_P")?,
+            top_k: parse_env("CLM_TOP_K")?,
+            max_tokens: parse_env("CLM_MAX_TOKENS")?,
+            stop: env("CLM_STOP")
+                .map(|stop| stop.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+            seed: parse_env("CLM_SEED")?,
+            candidates: parse_env("CLM_N")?,
+            thinking: env("CLM_THINK")
+                .map(|think| think.parse())
+                .transpose()
+                .map_err(|e| invalid(format!("CLM_THINK: {}", e)))?,
+        };
+
+        Ok(env_options.or(GenerationOptions {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            max_tokens: self.max_tokens,
+            stop: self.stop.clone().unwrap_or_default(),
+            seed: self.seed,
+            candidates: self.n,
+            thinking: self
+                .think
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(|e| invalid(format!("think: {}", e)))?,
+        }))
+    }
+
+    /// `CLM_AUTO_CONTINUE` overrides the profile's `auto_continue`.
+    pub fn auto_continue(&self) -> Result<Option<u32>> {
+        Ok(parse_env("CLM_AUTO_CONTINUE")?.or(self.auto_continue))
+    }
+
+    /// Tools from `CLM_TOOLS` (comma-separated), else from the profile.
+    pub fn tools(&self) -> Vec<String> {
+        match env("CLM_TOOLS") {
+            Some(tools) => tools.split(',').map(str::to_string).collect(),
+            None => self.tools.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Splits a `provider` or `provider:model` spec. Only the first colon counts,
+/// so Ollama tags such as `ollama:llama3.2:3b` keep theirs.
+pub fn parse_spec(spec: &str) -> (String, Option<String>) {
+    match spec.split_once(':') {
+        Some((provider, model)) => (provider.to_string(), Some(model.to_string())),
+        None => (spec.to_string(), None),
+    }
+}
+
+/// Directory for data `clm` keeps between runs, such as the usage ledger:
+/// `$CLM_DATA_DIR`, else `$XDG_DATA_HOME/clm`, else `~/.local/share/clm`.
+pub fn data_dir() -> Option<PathBuf> {
+    if let Some(path) = env("CLM_DATA_DIR") {
+        return Some(PathBuf::from(path));
+    }
+
+    let data_dir = env("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))?;
+
+    Some(data_dir.join("clm"))
+}
+
+/// Builds the connection settings for `provider`. API key, base URL and name
+/// come from the profile when it targets that provider, and from the
+/// provider's own environment variables otherwise.
+fn provider_config(
+    profile: &Profile,
+    provider: &str,
+    model: Option<String>,
+) -> Result<ProviderConfig> {
+    let provider = provider.to_lowercase();
+    let use_profile = profile
+        .provider
+        .as_deref()
+        .is_none_or(|p| p.eq_ignore_ascii_case(&provider));
+
+    let profile_api_key = match (&profile.api_key, &profile.api_key_command) {
+        _ if !use_profile => None,
+        (Some(key), _) => Some(key.clone()),
+        (None, Some(command)) => Some(run_key_command(command)?),
+        (None, None) => None,
+    };
+
+    let (api_key_var, base_url_var) = provider_vars(&provider);
+
+    let from_profile = |value: &Option<String>| value.clone().filter(|_| use_profile);
+
+    Ok(ProviderConfig {
+        api_key: profile_api_key.or_else(|| api_key_var.and_then(env)),
+        base_url: from_profile(&profile.base_url).or_else(|| base_url_var.and_then(env)),
+        name: from_profile(&profile.name).or_else(|| match provider.as_str() {
+            "custom" => env("CUSTOM_PROVIDER_NAME"),
+            _ => None,
+        }),
+        stream_usage: provider == "custom"
+            && profile
+                .stream_usage
+                .filter(|_| use_profile)
+                .or_else(|| {
+                    env("CUSTOM_PROVIDER_STREAM_USAGE")
+                        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+                })
+                .unwrap_or(false),
+        provider,
+        model,
+        max_retries: None,
+        timeouts: Timeouts::default(),
+    })
+}
+
+/// The environment variables holding a provider's API key and base URL.
+fn provider_vars(provider: &str) -> (Option<&'static str>, Option<&'static str>) {
+    match provider {
+        "openai" => (Some("OPENAI_API_KEY"), None),
+        "anthropic" => (Some("ANTHROPIC_API_KEY"), None),
+        "google" => (Some("GOOGLE_AI_API_KEY"), None),
+        "openrouter" => (Some("OPENROUTER_API_KEY"), None),
+        "ollama" => (None, Some("OLLAMA_BASE_URL")),
+        "custom" => (
+            Some("CUSTOM_PROVIDER_API_KEY"),
+            Some("CUSTOM_PROVIDER_API_URL"),
+        ),
+        _ => (None, None),
+    }
+}
+
+/// How to give a setting that a provider reported missing.
+pub fn missing_hint(provider: &str, setting: Setting) -> String {
+    let (api_key_var, base_url_var) = provider_vars(provider);
+    let (var, key) = match setting {
+        Setting::ApiKey => (api_key_var, "api_key"),
+        Setting::BaseUrl => (base_url_var, "base_url"),
+    };
+
+    match var {
+        Some(var) => format!("Set {} or `{}` in the profile", var, key),
+        None => format!("Set `{}` in the profile", key),
+    }
+}
+
+fn run_key_command(command: &str) -> Result<String> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| invalid(format!("Failed to run api_key_command: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(invalid(format!(
+            "api_key_command exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let key = String::from_utf8(output.stdout)?.trim().to_string();
+    if key.is_empty() {
+        return Err(invalid("api_key_command produced no output".to_string()));
+    }
+
+    Ok(key)
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str) -> Result<Option<T>> {
+    env(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| invalid(format!("{} must be a number, got '{}'", name, value)))
+        })
+        .transpose()
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// A configuration error, reported with its own exit status.
+fn invalid(message: String) -> anyhow::Error {
+    clm::Error::Config(message).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(provider: &str, model: &str) -> Profile {
+        Profile {
+            provider: Some(provider.to_string()),
+            model: Some(model.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn selection(provider: Option<&str>, model: Option<&str>) -> Selection {
+        Selection {
+            provider: provider.map(str::to_string),
+            model: model.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn some(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn resolve_uses_profile() {
+        let profile = profile("openai", "gpt-4.1");
+        assert_eq!(
+            profile.resolve(&Selection::default(), None, None),
+            ("openai".to_string(), some("gpt-4.1"))
+        );
+    }
+
+    #[test]
+    fn resolve_defaults_to_google() {
+        assert_eq!(
+            Profile::default().resolve(&Selection::default(), None, None),
+            ("google".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn resolve_env_provider_drops_profile_model() {
+        let profile = profile("openai", "gpt-4.1");
+        assert_eq!(
+            profile.resolve(&Selection::default(), some("ollama"), None),
+            ("ollama".to_string(), None)
+        );
+        assert_eq!(
+            profile.resolve(&Selection::default(), some("OpenAI"), None),
+            ("OpenAI".to_string(), some("gpt-4.1"))
+        );
+    }
+
+    #[test]
+    fn resolve_env_overrides_profile() {
+        let profile = profile("openai", "gpt-4.1");
+        assert_eq!(
+            profile.resolve(&Selection::default(), some("ollama"), some("qwen3")),
+            ("ollama".to_string(), some("qwen3"))
+        );
+        assert_eq!(
+            profile.resolve(&Selection::default(), None, some("gpt-4o")),
+            ("openai".to_string(), some("gpt-4o"))
+        );
+    }
+
+    #[test]
+    fn resolve_selection_overrides_env() {
+        let profile = profile("openai", "gpt-4.1");
+        assert_eq!(
+            profile.resolve(
+                &selection(Some("anthropic"), None),
+                some("ollama"),
+                some("qwen3")
+            ),
+            ("anthropic".to_string(), None)
+        );
+        assert_eq!(
+            profile.resolve(&selection(None, Some("o3")), some("ollama"), some("qwen3")),
+            ("ollama".to_string(), some("o3"))
+        );
+        assert_eq!(
+            profile.resolve(&selection(Some("openai"), None), None, None),
+            ("openai".to_string(), some("gpt-4.1"))
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::error::Error as _;
+use std::fmt;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Why a provider could not be set up or did not answer.
+///
+/// `{:#}` formats the error together with its causes, e.g. "error sending
+/// request for url (...): operation timed out".
+#[derive(Debug)]
+pub enum Error {
+    /// A setting is invalid, e.g. a negative timeout.
+    Config(String),
+    /// A setting the provider cannot work without was not given.
+    Missing {
+        /// The provider, e.g. `openai`.
+        provider: String,
+        setting: Setting,
+    },
+    /// The API rejected the credentials (401 or 403).
+    Auth(ApiError),
+    /// The API is rate limiting the requests (429).
+    RateLimit(ApiError),
+    /// The API failed on its side (5xx, or Anthropic's 529 "overloaded").
+    Server(ApiError),
+    /// The API rejected the request with any other error status, e.g. 400
+    /// for an unknown model.
+    Rejected(ApiError),
+    /// The provider refused to answer for safety reasons.
+    ContentFilter {
+        message: String,
+        /// The error status and body, when the refusal came as one.
+        response: Option<ApiError>,
+    },
+    /// A request or stream hit one of the configured timeouts.
+    Timeout(String),
+    /// The request could not be sent or the response could not be read.
+    Network(reqwest::Error),
+    /// The response is not the JSON the provider's API documents.
+    Parse(serde_json::Error),
+    /// The provider failed in a way not covered above, e.g. an error event in
+    /// a stream or an empty answer.
+    Provider(String),
+    /// The last provider of a fallback chain failed after the ones before it.
+    Fallback {
+        /// `provider` or `provider:model` of the last provider.
+        provider: String,
+        /// The providers that failed before it.
+        tried: Vec<String>,
+        source: Box<Error>,
+    },
+}
+
+/// A setting of `ProviderConfig` that a provider may require.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    ApiKey,
+    /// The custom provider's endpoint URL.
+    BaseUrl,
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Setting::ApiKey => "API key",
+            Setting::BaseUrl => "API URL",
+        })
+    }
+}
+
+/// An error status returned by a provider's API.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    /// The API that answered, e.g. `OpenAI API`.
+    pub api: String,
+    pub status: u16,
+    /// The response body as the provider sent it, usually a JSON error.
+    pub body: String,
+}
+
+impl ApiError {
+    /// Classifies the error by its status and, for refusals, its body.
+    pub fn into_error(self) -> Error {
+        match self.status {
+            401 | 403 => Error::Auth(self),
+            429 => Error::RateLimit(self),
+            500..=599 => Error::Server(self),
+            _ if self.is_content_filter() => Error::ContentFilter {
+                message: self.to_string(),
+                response: Some(self),
+            },
+            _ => Error::Rejected(self),
+        }
+    }
+
+    /// The provider's own explanation: `error.message` of OpenAI, Anthropic
+    /// and Google errors, `error` of Ollama errors, else the body itself.
+    pub fn message(&self) -> String {
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(&self.body) else {
+            return self.body.trim().to_string();
+        };
+
+        let error = json.get("error").unwrap_or(&json);
+        match error.get("message").unwrap_or(error).as_str() {
+            Some(message) => message.to_string(),
+            None => self.body.trim().to_string(),
+        }
+    }
+
+    /// OpenAI and Azure report refusals as `content_policy_violation` or
+    /// `content_filter` errors.
+    fn is_content_filter(&self) -> bool {
+        let body = self.body.to_lowercase();
+        body.contains("content_policy") || body.contains("content_filter")
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = reqwest::StatusCode::from_u16(self.status)
+            .map(|status| status.to_string())
+            .unwrap_or_else(|_| self.status.to_string());
+        write!(
+            f,
+            "{} request failed with status {}: {}",
+            self.api,
+            status,
+            self.message()
+        )
+    }
+}
+
+impl Error {
+    /// The error of the provider that failed last, looking through
+    /// `Fallback`.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Fallback { source, .. } => source.root(),
+            error => error,
+        }
+    }
+
+    /// A short name for the kind of error, e.g. `rate_limit`, describing
+    /// the provider that failed last.
+    pub fn kind(&self) -> &'static str {
+        match self.root() {
+            Error::Config(_) | Error::Missing { .. } => "config",
+            Error::Auth(_) => "auth",
+            Error::RateLimit(_) => "rate_limit",
+            Error::Server(_) => "server",
+            Error::Rejected(_) => "rejected",
+            Error::ContentFilter { .. } => "content_filter",
+            Error::Timeout(_) => "timeout",
+            Error::Network(_) => "network",
+            Error::Parse(_) => "parse",
+            Error::Provider(_) | Error::Fallback { .. } => "provider",
+        }
+    }
+
+    /// The error status and body, if the API answered with one.
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self.root() {
+            Error::Auth(e) | Error::RateLimit(e) | Error::Server(e) | Error::Rejected(e) => Some(e),
+            Error::ContentFilter { response, .. } => response.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The HTTP status the API answered with, if it was an error status.
+    pub fn status(&self) -> Option<u16> {
+        self.api_error().map(|e| e.status)
+    }
+
+    /// Whether the request or stream timed out.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self.root(), Error::Timeout(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message)
+            | Error::ContentFilter { message, .. }
+            | Error::Timeout(message)
+            | Error::Provider(message) => f.write_str(message)?,
+            Error::Auth(e) | Error::RateLimit(e) | Error::Server(e) | Error::Rejected(e) => {
+                write!(f, "{}", e)?
+            }
+            Error::Missing { provider, setting } => {
+                write!(f, "{} not set for {}", setting, provider)?
+            }
+            Error::Network(e) => write!(f, "{}", e)?,
+            Error::Parse(_) => f.write_str("Failed to parse the response")?,
+            Error::Fallback {
+                provider, tried, ..
+            } => write!(
+                f,
+                "{} failed after falling back from {}",
+                provider,
+                tried.join(", ")
+            )?,
+        }
+
+        if f.alternate() {
+            let mut source = self.source();
+            while let Some(cause) = source {
+                write!(f, ": {}", cause)?;
+                source = cause.source();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(e) => e.source(),
+            Error::Parse(e) => Some(e),
+            Error::Fallback { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Timeouts of the HTTP client become `Error::Timeout`, keeping the causes
+/// in the message.
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            Error::Timeout(format!("{:#}", Error::Network(error)))
+        } else {
+            Error::Network(error)
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Parse(error)
+    }
+}
@@ -0,0 +1,231 @@
+use crate::config;
+use anyhow::Result;
+use chrono::{DateTime, Local, Utc};
+use clm::providers::{AiResponse, ChatMessage, ChatRequest, Role, Usage};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
+
+/// Longest prompt excerpt shown by `history list` and `history search`.
+const EXCERPT_CHARS: usize = 60;
+
+/// A prompt and its answer, appended to the history after every query.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    /// Provider as selected with `--provider`, so the entry can be re-run.
+    pub provider: String,
+    pub model: String,
+    pub system: Option<String>,
+    /// The conversation sent, ending with the prompt that was answered.
+    /// Images are left out to keep the history small.
+    pub messages: Vec<ChatMessage>,
+    /// Number of images the conversation had.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub images: usize,
+    pub response: String,
+    pub usage: Usage,
+    pub duration_ms: u64,
+}
+
+impl Entry {
+    /// The last user message, i.e. the prompt this entry answered.
+    pub fn prompt(&self) -> &str {
+        self.messages
+            .iter()
+            .rev()
+            .find(|message| message.role == Role::User)
+            .map_or("", |message| message.content.as_str())
+    }
+}
+
+/// `history.jsonl` in the data directory.
+pub fn path() -> Option<PathBuf> {
+    config::data_dir().map(|dir| dir.join("history.jsonl"))
+}
+
+/// Appends the exchange to the history. Like the usage ledger, failures only
+/// produce a warning.
+pub fn record(provider: &str, request: &ChatRequest, response: &AiResponse) {
+    if let Err(e) = append(provider, request, response) {
+        eprintln!("Warning: failed to record history: {}", e);
+    }
+}
+
+fn append(provider: &str, request: &ChatRequest, response: &AiResponse) -> Result<()> {
+    let Some(path) = path() else {
+        return Ok(());
+    };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(&path)?;
+    // Held until the entry is written, so that concurrent runs get distinct
+    // ids.
+    file.lock()?;
+
+    let entry = Entry {
+        id: count_lines(&file)? + 1,
+        timestamp: Utc::now(),
+        provider: provider.to_string(),
+        model: response.model.clone(),
+        system: request.system.clone(),
+        messages: request
+            .messages
+            .iter()
+            .map(|message| ChatMessage {
+                images: Vec::new(),
+                ..message.clone()
+            })
+            .collect(),
+        images: request
+            .messages
+            .iter()
+            .map(|message| message.images.len())
+            .sum(),
+        response: response.content.clone(),
+        usage: response.usage,
+        duration_ms: response.duration.as_millis() as u64,
+    };
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+
+    Ok(())
+}
+
+/// Entries are numbered by their line, which is cheaper than reading the
+/// last id back.
+fn count_lines(mut file: &std::fs::File) -> Result<u64> {
+    let mut buffer = [0; 64 * 1024];
+    let mut lines = 0;
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(lines);
+        }
+        lines += buffer[..read].iter().filter(|&&byte| byte == b'\n').count() as u64;
+    }
+}
+
+fn is_zero(count: &usize) -> bool {
+    *count == 0
+}
+
+/// Reads all history entries, oldest first. Malformed lines are skipped.
+pub fn read() -> Result<Vec<Entry>> {
+    let Some(path) = path() else {
+        return Ok(Vec::new());
+    };
+
+    let file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => anyhow::bail!("Failed to read history {}: {}", path.display(), e),
+    };
+
+    let mut entries = Vec::new();
+    for line in std::io::BufReader::new(file).lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+pub fn find(id: u64) -> Result<Entry> {
+    read()?
+        .into_iter()
+        .find(|entry| entry.id == id)
+        .ok_or_else(|| anyhow::anyhow!("No history entry with id {}", id))
+}
+
+/// Prints the `limit` most recent entries, newest first.
+pub fn list(limit: usize) -> Result<()> {
+    print_entries(read()?.iter().rev().take(limit));
+    Ok(())
+}
+
+/// Prints entries whose prompt or response contains `text`, ignoring case.
+pub fn search(text: &str, limit: usize) -> Result<()> {
+    let needle = text.to_lowercase();
+    let entries = read()?;
+    let matches = entries.iter().rev().filter(|entry| {
+        entry.prompt().to_lowercase().contains(&needle)
+            || entry.response.to_lowercase().contains(&needle)
+    });
+
+    print_entries(matches.take(limit));
+    Ok(())
+}
+
+pub fn show(id: u64) -> Result<()> {
+    let entry = find(id)?;
+
+    println!(
+        "#{} | {} | Model: {} | Provider: {} | Time: {:.2}s",
+        entry.id,
+        format_timestamp(&entry.timestamp),
+        entry.model,
+        entry.provider,
+        entry.duration_ms as f64 / 1000.0
+    );
+    if entry.messages.len() > 1 {
+        println!(
+            "(turn {} of a chat)",
+            entry
+                .messages
+                .iter()
+                .filter(|message| message.role == Role::User)
+                .count()
+        );
+    }
+    println!("\n> {}\n\n{}", entry.prompt(), entry.response);
+
+    Ok(())
+}
+
+fn print_entries<'a>(entries: impl Iterator<Item = &'a Entry>) {
+    let mut empty = true;
+
+    for entry in entries {
+        empty = false;
+        println!(
+            "{:>5}  {}  {}:{}  {}",
+            entry.id,
+            format_timestamp(&entry.timestamp),
+            entry.provider,
+            entry.model,
+            excerpt(entry.prompt())
+        );
+    }
+
+    if empty {
+        println!("No matching history entries.");
+    }
+}
+
+fn format_timestamp(timestamp: &DateTime<Utc>) -> String {
+    timestamp
+        .with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+/// First line of `text`, shortened to `EXCERPT_CHARS`.
+fn excerpt(text: &str) -> String {
+    let line = text.trim().lines().next().unwrap_or("");
+    if line.chars().count() > EXCERPT_CHARS {
+        let short: String = line.chars().take(EXCERPT_CHARS - 3).collect();
+        format!("{}...", short)
+    } else {
+        line.to_string()
+    }
+}
@@ -0,0 +1,594 @@
+use crate::output;
+use anyhow::Result;
+use base64::Engine;
+use clm::providers::{ChatRequest, Image};
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Upper bound on piped input, to avoid sending huge files by accident.
+const MAX_STDIN_BYTES: u64 = 1024 * 1024;
+
+/// How long piped input may take to start when a prompt was given. CI
+/// runners and `ssh` without `-n` leave stdin open without ever writing to it.
+const STDIN_WAIT: Duration = Duration::from_secs(3);
+
+/// Largest image accepted by `--image`, the limit of the OpenAI API.
+const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// Combined size of `--file` attachments above which a warning is printed.
+pub const DEFAULT_FILE_BUDGET: u64 = 100 * 1024;
+
+/// A text file attached with `--file`.
+pub struct Attachment {
+    pub path: String,
+    pub text: String,
+}
+
+/// Reads piped stdin, if any. Returns `None` when stdin is a terminal or the
+/// pipe is empty. With a prompt, stdin is ignored if nothing arrives on it
+/// within `STDIN_WAIT`; without one, it is read until it is closed.
+pub fn read_stdin(has_prompt: bool) -> Result<Option<String>> {
+    if std::io::stdin().is_terminal() {
+        return Ok(None);
+    }
+
+    let (started_tx, started) = mpsc::channel();
+    let (done_tx, done) = mpsc::channel();
+    // Left blocked on the pipe if it is abandoned; it ends with the process.
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut buffer = vec![0; 8192];
+        let first = stdin.read(&mut buffer);
+        let _ = started_tx.send(());
+
+        let result = first.and_then(|read| {
+            buffer.truncate(read);
+            if read > 0 {
+                stdin
+                    .take(MAX_STDIN_BYTES + 1 - read as u64)
+                    .read_to_end(&mut buffer)?;
+            }
+            Ok(buffer)
+        });
+        let _ = done_tx.send(result);
+    });
+
+    if has_prompt && started.recv_timeout(STDIN_WAIT).is_err() {
+        eprintln!(
+            "Warning: nothing arrived on stdin within {}s; ignoring it",
+            STDIN_WAIT.as_secs()
+        );
+        return Ok(None);
+    }
+
+    stdin_text(done.recv()??)
+}
+
+/// Checks piped input: at most `MAX_STDIN_BYTES` of UTF-8 text.
+fn stdin_text(buffer: Vec<u8>) -> Result<Option<String>> {
+    if buffer.len() as u64 > MAX_STDIN_BYTES {
+        anyhow::bail!(
+            "Input on stdin exceeds the limit of {} KiB",
+            MAX_STDIN_BYTES / 1024
+        );
+    }
+
+    if buffer.contains(&0) {
+        anyhow::bail!("Input on stdin looks like binary data; only text can be sent");
+    }
+
+    let text = String::from_utf8(buffer)
+        .map_err(|_| anyhow::anyhow!("Input on stdin is not valid UTF-8 text"))?;
+
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(text))
+}
+
+/// Combines the prompt from the command line with piped context. The context
+/// is wrapped in a fenced block long enough not to clash with any backticks
+/// it contains.
+pub fn combine(prompt: &str, context: Option<&str>) -> String {
+    let Some(context) = context else {
+        return prompt.to_string();
+    };
+
+    if prompt.is_empty() {
+        return context.trim_end().to_string();
+    }
+
+    let fence = "`".repeat(longest_backtick_run(context).max(2) + 1);
+
+    format!("{}\n\n{}\n{}\n{}", prompt, fence, context.trim_end(), fence)
+}
+
+/// Reads the files named by `patterns`, which may be paths or globs such as
+/// `src/**/*.rs`. Binary files are skipped with a warning, and a warning is
+/// printed when the files add up to more than `budget` bytes.
+pub fn read_files(patterns: &[String], budget: u64) -> Result<Vec<Attachment>> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for pattern in patterns {
+        for path in expand(pattern)? {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+
+    let mut attachments = Vec::new();
+    for path in paths {
+        let bytes = std::fs::read(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+
+        let text = match String::from_utf8(bytes) {
+            Ok(text) if !text.contains('\0') => text,
+            _ => {
+                eprintln!("Warning: skipping binary file {}", path.display());
+                continue;
+            }
+        };
+
+        attachments.push(Attachment {
+            path: path.display().to_string(),
+            text,
+        });
+    }
+
+    if let Some(warning) = budget_warning(&attachments, budget) {
+        eprintln!("Warning: {}", warning);
+    }
+
+    Ok(attachments)
+}
+
+/// Describes how far the attachments exceed `budget` bytes, if they do.
+fn budget_warning(attachments: &[Attachment], budget: u64) -> Option<String> {
+    let total: u64 = attachments.iter().map(|a| a.text.len() as u64).sum();
+    (total > budget).then(|| {
+        format!(
+            "attached files total {} KiB (about {} tokens), over the budget of {} KiB",
+            total.div_ceil(1024),
+            total / 4,
+            budget / 1024
+        )
+    })
+}
+
+/// Appends the attachments to the prompt, each in a fenced block labelled
+/// with its path and tagged with the language of its extension.
+pub fn attach(prompt: &str, attachments: &[Attachment]) -> String {
+    let mut text = prompt.to_string();
+
+    for attachment in attachments {
+        let fence = "`".repeat(longest_backtick_run(&attachment.text).max(2) + 1);
+        if !text.is_empty() {
+            text.push_str("\n\n");
+        }
+        text.push_str(&format!(
+            "File: {}\n{}{}\n{}\n{}",
+            attachment.path,
+            fence,
+            language(Path::new(&attachment.path)),
+            attachment.text.trim_end(),
+            fence
+        ));
+    }
+
+    text
+}
+
+/// Reads an image for `--image`. The format is detected from the file's
+/// contents; PNG, JPEG, GIF and WebP are supported by all providers.
+pub fn read_image(path: &Path) -> Result<Image> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+
+    if bytes.len() > MAX_IMAGE_BYTES {
+        anyhow::bail!(
+            "{} exceeds the image size limit of {} MiB",
+            path.display(),
+            MAX_IMAGE_BYTES / 1024 / 1024
+        );
+    }
+
+    let Some(mime_type) = image_type(&bytes) else {
+        anyhow::bail!("{} is not a PNG, JPEG, GIF or WebP image", path.display());
+    };
+
+    Ok(Image {
+        mime_type: mime_type.to_string(),
+        data: base64::engine::general_purpose::STANDARD.encode(&bytes),
+    })
+}
+
+/// The MIME type of an image, detected from its magic bytes.
+fn image_type(bytes: &[u8]) -> Option<&'static str> {
+    Some(match bytes {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => "image/webp",
+        _ => return None,
+    })
+}
+
+/// Explains a rejected request that included images, since providers word
+/// this differently and some models do not accept images at all.
+pub fn image_hint(error: anyhow::Error, request: &ChatRequest) -> anyhow::Error {
+    if request
+        .messages
+        .iter()
+        .all(|message| message.images.is_empty())
+    {
+        return error;
+    }
+
+    let rejected = output::provider_error(&error)
+        .and_then(clm::Error::api_error)
+        .is_some_and(|e| {
+            matches!(e.status, 400 | 415 | 422) || e.body.to_lowercase().contains("image")
+        });
+
+    if rejected {
+        error.context(
+            "The request with images was rejected; check that the model supports image input",
+        )
+    } else {
+        error
+    }
+}
+
+/// The files a `--file` argument refers to. Existing paths are taken as they
+/// are; anything else is treated as a glob, whose directories are ignored.
+fn expand(pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(pattern);
+
+    if path.is_dir() {
+        anyhow::bail!(
+            "{} is a directory; use a glob such as '{}/**/*' to attach its files",
+            pattern,
+            pattern.trim_end_matches('/')
+        );
+    }
+    if path.exists() || !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut paths = Vec::new();
+    for entry in glob::glob(pattern)
+        .map_err(|e| anyhow::anyhow!("Invalid file pattern '{}': {}", pattern, e))?
+    {
+        let path = entry?;
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+
+    if paths.is_empty() {
+        anyhow::bail!("No files match '{}'", pattern);
+    }
+
+    Ok(paths)
+}
+
+/// The Markdown language tag for a file, or an empty string if unknown.
+fn language(path: &Path) -> &'static str {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some("Dockerfile") => return "dockerfile",
+        Some("Makefile" | "makefile" | "GNUmakefile") => return "make",
+        _ => {}
+    }
+
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "jsx",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "tsx",
+        "go" => "go",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "scala" => "scala",
+        "swift" => "swift",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => "cpp",
+        "cs" => "csharp",
+        "rb" => "ruby",
+        "php" => "php",
+        "lua" => "lua",
+        "dart" => "dart",
+        "ex" | "exs" => "elixir",
+        "erl" => "erlang",
+        "hs" => "haskell",
+        "ml" | "mli" => "ocaml",
+        "zig" => "zig",
+        "nix" => "nix",
+        "r" => "r",
+        "sh" | "bash" => "bash",
+        "zsh" => "zsh",
+        "fish" => "fish",
+        "ps1" => "powershell",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "vue" => "vue",
+        "svelte" => "svelte",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "xml" => "xml",
+        "md" => "markdown",
+        "proto" => "protobuf",
+        "graphql" | "gql" => "graphql",
+        "tf" | "hcl" => "hcl",
+        _ => "",
+    }
+}
+
+fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for a test's files.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("clm-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(dir: &Path, name: &str, contents: &[u8]) -> String {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path.display().to_string()
+    }
+
+    fn attachment(path: &str, text: &str) -> Attachment {
+        Attachment {
+            path: path.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn combine_without_context() {
+        assert_eq!(combine("explain", None), "explain");
+    }
+
+    #[test]
+    fn combine_context_alone_is_the_prompt() {
+        assert_eq!(combine("", Some("error: oops\n\n")), "error: oops");
+    }
+
+    #[test]
+    fn combine_wraps_context_in_a_fence() {
+        assert_eq!(
+            combine("explain", Some("error: oops\n")),
+            "explain\n\n```\nerror: oops\n```"
+        );
+    }
+
+    #[test]
+    fn combine_fence_outlasts_backticks_in_context() {
+        assert_eq!(
+            combine("review", Some("```rust\nfn main() {}\n```\n")),
+            "review\n\n````\n```rust\nfn main() {}\n```\n````"
+        );
+    }
+
+    #[test]
+    fn stdin_up_to_the_limit() {
+        let text = "a".repeat(MAX_STDIN_BYTES as usize);
+        assert_eq!(stdin_text(text.clone().into_bytes()).unwrap(), Some(text));
+    }
+
+    #[test]
+    fn stdin_over_the_limit_rejected() {
+        let bytes = vec![b'a'; MAX_STDIN_BYTES as usize + 1];
+        let error = stdin_text(bytes).unwrap_err().to_string();
+        assert!(error.contains("exceeds the limit of 1024 KiB"), "{error}");
+    }
+
+    #[test]
+    fn binary_stdin_rejected() {
+        let error = stdin_text(b"PK\x03\x04\0\0".to_vec())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("binary"), "{error}");
+
+        let error = stdin_text(vec![0xC3, 0x28]).unwrap_err().to_string();
+        assert!(error.contains("UTF-8"), "{error}");
+    }
+
+    #[test]
+    fn blank_stdin_ignored() {
+        assert_eq!(stdin_text(b" \n\t\n".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn globs_expand_to_matching_files() {
+        let dir = scratch("globs");
+        write(&dir, "a.rs", b"fn a() {}");
+        write(&dir, "b.rs", b"fn b() {}");
+        write(&dir, "notes.txt", b"notes");
+        write(&dir, "sub/c.rs", b"fn c() {}");
+
+        let files = |pattern: &str| {
+            let mut paths: Vec<PathBuf> = expand(&format!("{}/{}", dir.display(), pattern))
+                .unwrap()
+                .into_iter()
+                .map(|path| path.strip_prefix(&dir).unwrap().to_path_buf())
+                .collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(files("*.rs"), [Path::new("a.rs"), Path::new("b.rs")]);
+        assert_eq!(
+            files("**/*.rs"),
+            [Path::new("a.rs"), Path::new("b.rs"), Path::new("sub/c.rs")]
+        );
+        assert_eq!(files("s*/*"), [Path::new("sub/c.rs")]);
+    }
+
+    #[test]
+    fn globs_matching_only_directories_fail() {
+        let dir = scratch("directories");
+        write(&dir, "sub/c.rs", b"fn c() {}");
+
+        let error = expand(&format!("{}/s*", dir.display()))
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("No files match"), "{error}");
+
+        let error = expand(&format!("{}/sub", dir.display()))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("is a directory"), "{error}");
+    }
+
+    #[test]
+    fn plain_paths_are_not_globbed() {
+        assert_eq!(
+            expand("no/such/file.rs").unwrap(),
+            [PathBuf::from("no/such/file.rs")]
+        );
+    }
+
+    #[test]
+    fn read_files_skips_binary_files_and_duplicates() {
+        let dir = scratch("read");
+        let text = write(&dir, "main.rs", b"fn main() {}\n");
+        write(&dir, "logo.png", b"\x89PNG\r\n\x1a\n\0\0");
+
+        let attachments =
+            read_files(&[text.clone(), format!("{}/*", dir.display())], 1024).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].path, text);
+        assert_eq!(attachments[0].text, "fn main() {}\n");
+    }
+
+    #[test]
+    fn budget_warns_only_when_exceeded() {
+        let attachments = [
+            attachment("a.txt", &"a".repeat(1024)),
+            attachment("b.txt", "b"),
+        ];
+        assert_eq!(budget_warning(&attachments, 1025), None);
+        assert_eq!(
+            budget_warning(&attachments, 1024).as_deref(),
+            Some("attached files total 2 KiB (about 256 tokens), over the budget of 1 KiB")
+        );
+    }
+
+    #[test]
+    fn attach_labels_and_tags_each_file() {
+        let attachments = [
+            attachment("src/main.rs", "fn main() {}\n"),
+            attachment("Dockerfile", "FROM rust"),
+        ];
+        assert_eq!(
+            attach("review", &attachments),
+            "review\n\nFile: src/main.rs\n```rust\nfn main() {}\n```\n\nFile: Dockerfile\n```dockerfile\nFROM rust\n```"
+        );
+    }
+
+    #[test]
+    fn attach_fence_outlasts_backticks_in_file() {
+        assert_eq!(
+            attach("", &[attachment("README.md", "````sh\nls\n````")]),
+            "File: README.md\n`````markdown\n````sh\nls\n````\n`````"
+        );
+    }
+
+    #[test]
+    fn language_from_name_or_extension() {
+        assert_eq!(language(Path::new("lib/App.TSX")), "tsx");
+        assert_eq!(language(Path::new("Makefile")), "make");
+        assert_eq!(language(Path::new("script.py")), "python");
+        assert_eq!(language(Path::new("data.bin")), "");
+        assert_eq!(language(Path::new("LICENSE")), "");
+    }
+
+    #[test]
+    fn image_types_from_magic_bytes() {
+        assert_eq!(image_type(b"\x89PNG\r\n\x1a\n"), Some("image/png"));
+        assert_eq!(
+            image_type(b"\xFF\xD8\xFF\xE0\0\x10JFIF"),
+            Some("image/jpeg")
+        );
+        assert_eq!(image_type(b"GIF89a"), Some("image/gif"));
+        assert_eq!(image_type(b"RIFF\x24\0\0\0WEBPVP8 "), Some("image/webp"));
+        // A RIFF file that is not WebP, e.g. a WAV recording.
+        assert_eq!(image_type(b"RIFF\x24\0\0\0WAVEfmt "), None);
+        assert_eq!(image_type(b"BM\x36\0"), None);
+        assert_eq!(image_type(b""), None);
+    }
+
+    #[test]
+    fn read_image_encodes_base64() {
+        let dir = scratch("image");
+        let path = write(&dir, "dot.gif", b"GIF89a\x01\0");
+
+        let image = read_image(Path::new(&path)).unwrap();
+        assert_eq!(image.mime_type, "image/gif");
+        assert_eq!(image.data, "R0lGODlhAQA=");
+    }
+
+    #[test]
+    fn read_image_rejects_other_files() {
+        let dir = scratch("not-image");
+        let path = write(&dir, "photo.png", b"<svg></svg>");
+
+        let error = read_image(Path::new(&path)).unwrap_err().to_string();
+        assert!(
+            error.ends_with("is not a PNG, JPEG, GIF or WebP image"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn read_image_rejects_large_files() {
+        let dir = scratch("large-image");
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        bytes.resize(MAX_IMAGE_BYTES + 1, 0);
+        let path = write(&dir, "huge.png", &bytes);
+
+        let error = read_image(Path::new(&path)).unwrap_err().to_string();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            error.ends_with("exceeds the image size limit of 20 MiB"),
+            "{error}"
+        );
+    }
+}
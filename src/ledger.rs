@@ -0,0 +1,183 @@
+use crate::config;
+use crate::pricing::format_cost;
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use clm::providers::AiResponse;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+/// One line of the usage ledger, appended after every answered query.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub timestamp: DateTime<Utc>,
+    pub provider: String,
+    pub model: String,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub cached_tokens: u32,
+    pub cost_usd: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum GroupBy {
+    #[default]
+    Day,
+    Provider,
+    Model,
+}
+
+/// `usage.jsonl` in the data directory.
+pub fn path() -> Option<PathBuf> {
+    config::data_dir().map(|dir| dir.join("usage.jsonl"))
+}
+
+/// Appends the response to the ledger. Failures only produce a warning, since
+/// the answer has already been delivered.
+pub fn record(response: &AiResponse, cost: Option<f64>) {
+    let entry = Entry {
+        timestamp: Utc::now(),
+        provider: response.provider.clone(),
+        model: response.model.clone(),
+        input_tokens: response.usage.input_tokens.unwrap_or(0),
+        output_tokens: response.usage.output_tokens.unwrap_or(0),
+        cached_tokens: response.usage.cached_tokens.unwrap_or(0),
+        cost_usd: cost,
+    };
+
+    if let Err(e) = append(&entry) {
+        eprintln!("Warning: failed to record usage: {}", e);
+    }
+}
+
+fn append(entry: &Entry) -> Result<()> {
+    let Some(path) = path() else {
+        return Ok(());
+    };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+
+    Ok(())
+}
+
+/// Reads all ledger entries. A missing ledger is empty; malformed lines are
+/// skipped so a single bad write does not hide the rest of the history.
+pub fn read() -> Result<Vec<Entry>> {
+    let Some(path) = path() else {
+        return Ok(Vec::new());
+    };
+
+    let file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => anyhow::bail!("Failed to read usage ledger {}: {}", path.display(), e),
+    };
+
+    let mut entries = Vec::new();
+    for line in std::io::BufReader::new(file).lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+#[derive(Default)]
+struct Totals {
+    requests: usize,
+    input_tokens: u64,
+    output_tokens: u64,
+    cost: f64,
+    unpriced: usize,
+}
+
+impl Totals {
+    fn add(&mut self, entry: &Entry) {
+        self.requests += 1;
+        self.input_tokens += u64::from(entry.input_tokens);
+        self.output_tokens += u64::from(entry.output_tokens);
+        match entry.cost_usd {
+            Some(cost) => self.cost += cost,
+            None => self.unpriced += 1,
+        }
+    }
+}
+
+/// Prints estimated spend from the ledger, grouped by local day, provider or
+/// model, optionally starting at `since`.
+pub fn report(by: GroupBy, since: Option<NaiveDate>) -> Result<()> {
+    let mut groups: BTreeMap<String, Totals> = BTreeMap::new();
+    let mut total = Totals::default();
+
+    for entry in read()? {
+        let day = entry.timestamp.with_timezone(&Local).date_naive();
+        if since.is_some_and(|since| day < since) {
+            continue;
+        }
+
+        let key = match by {
+            GroupBy::Day => day.to_string(),
+            GroupBy::Provider => entry.provider.clone(),
+            GroupBy::Model => format!("{}:{}", entry.provider, entry.model),
+        };
+
+        groups.entry(key).or_default().add(&entry);
+        total.add(&entry);
+    }
+
+    if groups.is_empty() {
+        println!("No usage recorded yet.");
+        return Ok(());
+    }
+
+    let heading = match by {
+        GroupBy::Day => "Day",
+        GroupBy::Provider => "Provider",
+        GroupBy::Model => "Model",
+    };
+    let width = groups
+        .keys()
+        .map(String::len)
+        .chain([heading.len(), "Total".len()])
+        .max()
+        .unwrap_or(0);
+
+    println!(
+        "{:<width$}  {:>8}  {:>12}  {:>12}  {:>10}",
+        heading, "Requests", "Input", "Output", "Cost"
+    );
+    for (key, totals) in &groups {
+        print_row(key, totals, width);
+    }
+    print_row("Total", &total, width);
+
+    if total.unpriced > 0 {
+        println!(
+            "\n{} of {} requests had no known price and are not included in the cost.",
+            total.unpriced, total.requests
+        );
+    }
+
+    Ok(())
+}
+
+fn print_row(label: &str, totals: &Totals, width: usize) {
+    println!(
+        "{:<width$}  {:>8}  {:>12}  {:>12}  {:>10}",
+        label,
+        totals.requests,
+        totals.input_tokens,
+        totals.output_tokens,
+        format_cost(totals.cost)
+    );
+}
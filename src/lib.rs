@@ -0,0 +1,39 @@
+//! Chat clients for OpenAI, Anthropic, Google, OpenRouter, Ollama and
+//! OpenAI-compatible APIs behind one [`AiProvider`] trait, as used by the
+//! `clm` command line tool.
+//!
+//! Providers are set up from explicit settings and never read environment
+//! variables:
+//!
+//! ```no_run
+//! use clm::providers::openai::OpenAiProvider;
+//! use clm::{AiProvider, ChatMessage, ChatRequest};
+//!
+//! # async fn example() -> clm::Result<()> {
+//! let provider = OpenAiProvider::builder()
+//!     .api_key("sk-...")
+//!     .model("gpt-4.1-mini")
+//!     .build()?;
+//!
+//! let response = provider
+//!     .query(&ChatRequest {
+//!         messages: vec![ChatMessage::user("Name three primary colors")],
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! println!("{}", response.content);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`get_provider`] picks the provider named in a [`ProviderConfig`], and
+//! [`get_provider_chain`] falls back along several of them.
+
+pub mod error;
+pub mod providers;
+
+pub use error::{ApiError, Error, Result, Setting};
+pub use providers::{
+    AiProvider, AiResponse, ChatMessage, ChatRequest, Chunk, GenerationOptions, ProviderBuilder,
+    ProviderConfig, get_provider, get_provider_chain,
+};
@@ -1,58 +1,584 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use clm::providers::http::Timeouts;
+use clm::providers::{self, ChatMessage, ChatRequest, GenerationOptions, Thinking};
+use std::path::PathBuf;
 
-mod providers;
+mod chat;
+mod compare;
+mod config;
+mod history;
+mod input;
+mod ledger;
+mod output;
+mod pricing;
+mod schema;
+mod serve;
+mod toolbox;
+
+use output::{Delivery, OutputFormat};
+use pricing::Pricing;
+use schema::Schema;
+use toolbox::Toolbox;
+
+const DEFAULT_SYSTEM_PROMPT: &str = "You are a helpful assistant. You will receive a prompt and you will respond with a short, concise answer. If you respond with a code block, please format it using markdown syntax. Before the code block, please include a brief explanation of what the code does. Separate the explanation from the code block using --- dashes.";
+
+/// Exit status when a request times out, the same as timeout(1) uses.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// The exit status for a provider error, as documented in the README. Any
+/// other failure exits with 1.
+fn exit_code(error: &clm::Error) -> i32 {
+    match error.root() {
+        clm::Error::Config(_) | clm::Error::Missing { .. } => 3,
+        clm::Error::Auth(_) => 4,
+        clm::Error::RateLimit(_) => 5,
+        clm::Error::Server(_) => 6,
+        clm::Error::Network(_) => 7,
+        clm::Error::ContentFilter { .. } => 8,
+        clm::Error::Parse(_) => 9,
+        clm::Error::Rejected(_) => 10,
+        clm::Error::Timeout(_) => TIMEOUT_EXIT_CODE,
+        clm::Error::Provider(_) | clm::Error::Fallback { .. } => 1,
+    }
+}
 
 #[derive(Parser)]
 #[command(name = "clm")]
 #[command(about = "Command Line LLM tool")]
 struct Cli {
-    /// The question or prompt to send to the AI
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Output format for the answer and metadata
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Wait for the full response instead of printing it as it arrives
+    #[arg(long, global = true)]
+    no_stream: bool,
+
+    /// Provider to use (overrides CLM_PROVIDER)
+    #[arg(short, long, global = true)]
+    provider: Option<String>,
+
+    /// Model to use (overrides CLM_MODEL). For `compare`, a provider:model
+    /// to include; repeat it for each model
+    #[arg(short, long, global = true)]
+    model: Vec<String>,
+
+    /// Named profile from the config file (overrides CLM_PROFILE)
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Retries for rate limits, overloaded servers and connection errors
+    /// (overrides CLM_MAX_RETRIES, default 3)
+    #[arg(long, global = true, value_name = "N")]
+    max_retries: Option<u32>,
+
+    /// Only use the selected provider, ignoring CLM_FALLBACK and the
+    /// profile's fallback list
+    #[arg(long, global = true)]
+    no_fallback: bool,
+
+    /// Seconds to wait for a connection (overrides CLM_CONNECT_TIMEOUT,
+    /// default 10, 0 to disable)
+    #[arg(long, global = true, value_name = "SECS")]
+    connect_timeout: Option<f64>,
+
+    /// Seconds to wait for any read from the server (overrides
+    /// CLM_READ_TIMEOUT, default 300, 0 to disable)
+    #[arg(long, global = true, value_name = "SECS")]
+    read_timeout: Option<f64>,
+
+    /// Seconds the whole request may take (overrides CLM_TIMEOUT, no limit by
+    /// default)
+    #[arg(long, global = true, value_name = "SECS")]
+    timeout: Option<f64>,
+
+    /// Seconds a streaming response may stall between chunks (overrides
+    /// CLM_STREAM_IDLE_TIMEOUT, default 120, 0 to disable)
+    #[arg(long, global = true, value_name = "SECS")]
+    stream_idle_timeout: Option<f64>,
+
+    /// System prompt to use instead of the default (overrides CLM_SYSTEM_PROMPT)
+    #[arg(long, global = true, conflicts_with = "system_file")]
+    system: Option<String>,
+
+    /// Read the system prompt from a file
+    #[arg(long, global = true, value_name = "PATH")]
+    system_file: Option<PathBuf>,
+
+    /// Sampling temperature; lower is more deterministic (overrides
+    /// CLM_TEMPERATURE)
+    #[arg(long, global = true, value_name = "T")]
+    temperature: Option<f32>,
+
+    /// Nucleus sampling: only consider the tokens making up this share of the
+    /// probability (overrides CLM_TOP_P)
+    #[arg(long, global = true, value_name = "P")]
+    top_p: Option<f32>,
+
+    /// Only consider the K most likely tokens (overrides CLM_TOP_K; not
+    /// supported by OpenAI)
+    #[arg(long, global = true, value_name = "K")]
+    top_k: Option<u32>,
+
+    /// Upper bound on the tokens in the answer (overrides CLM_MAX_TOKENS;
+    /// Anthropic defaults to 8192)
+    #[arg(long, global = true, value_name = "N")]
+    max_tokens: Option<u32>,
+
+    /// Stop the answer when the model generates this text. Can be repeated
+    /// (overrides CLM_STOP)
+    #[arg(long, global = true, value_name = "TEXT")]
+    stop: Vec<String>,
+
+    /// Seed for reproducible sampling where the provider supports it
+    /// (overrides CLM_SEED; not supported by Anthropic)
+    #[arg(long, global = true, value_name = "N")]
+    seed: Option<u64>,
+
+    /// Ask for N alternative answers, printed one after another (overrides
+    /// CLM_N; supported by OpenAI-compatible providers and Google)
+    #[arg(long = "n", global = true, value_name = "N")]
+    candidates: Option<u32>,
+
+    /// Let a reasoning model think first, with a token budget or low, medium
+    /// or high (overrides CLM_THINK)
+    #[arg(long, global = true, value_name = "BUDGET|LEVEL")]
+    think: Option<Thinking>,
+
+    /// Leave the model's reasoning out of text output
+    #[arg(long, global = true)]
+    hide_thinking: bool,
+
+    /// Continue an answer cut off at the token limit with up to N follow-up
+    /// requests (overrides CLM_AUTO_CONTINUE, default 0)
+    #[arg(long, global = true, value_name = "N")]
+    auto_continue: Option<u32>,
+
+    /// Built-in tool the model may call: current_time, read_file,
+    /// list_directory or shell. Repeat it or separate names with commas
+    /// (overrides CLM_TOOLS)
+    #[arg(
+        long = "tool",
+        global = true,
+        value_name = "NAME",
+        value_delimiter = ','
+    )]
+    tools: Vec<String>,
+
+    /// Attach a text file to the prompt. Accepts globs such as 'src/**/*.rs'
+    /// and can be repeated
+    #[arg(short, long = "file", global = true, value_name = "PATH")]
+    files: Vec<String>,
+
+    /// Attach a PNG, JPEG, GIF or WebP image for a vision model to look at.
+    /// Can be repeated
+    #[arg(long = "image", global = true, value_name = "PATH")]
+    images: Vec<PathBuf>,
+
+    /// Warn when attached files exceed this many bytes (overrides
+    /// CLM_FILE_BUDGET, default 102400)
+    #[arg(long, global = true, value_name = "BYTES")]
+    file_budget: Option<u64>,
+
+    /// Answer with JSON matching the JSON Schema in this file. The answer is
+    /// validated and the command fails if it does not match
+    #[arg(long, global = true, value_name = "PATH")]
+    schema: Option<PathBuf>,
+
+    /// The question or prompt to send to the AI. Text piped on stdin is
+    /// appended as context
     #[arg(trailing_var_arg = true)]
     prompt: Vec<String>,
 }
 
+impl Cli {
+    fn model(&self) -> Result<Option<String>> {
+        match self.model.as_slice() {
+            [] => Ok(None),
+            [model] => Ok(Some(model.clone())),
+            _ => anyhow::bail!(
+                "--model can only be given once (use `clm compare` to ask several models)"
+            ),
+        }
+    }
+
+    fn selection(&self) -> Result<config::Selection> {
+        Ok(config::Selection {
+            provider: self.provider.clone(),
+            model: self.model()?,
+            max_retries: self.max_retries,
+            timeouts: self.timeouts(),
+        })
+    }
+
+    /// Builds the user message from the prompt arguments, piped stdin and the
+    /// `--file` and `--image` attachments.
+    fn user_message(&self, prompt: &[String], profile: &config::Profile) -> Result<ChatMessage> {
+        let budget = match self.file_budget {
+            Some(budget) => budget,
+            None => profile.file_budget()?.unwrap_or(input::DEFAULT_FILE_BUDGET),
+        };
+        let attachments = input::read_files(&self.files, budget)?;
+        let images = self
+            .images
+            .iter()
+            .map(|path| input::read_image(path))
+            .collect::<Result<Vec<_>>>()?;
+        let context = input::read_stdin(!prompt.is_empty())?;
+
+        if prompt.is_empty() && context.is_none() && attachments.is_empty() && images.is_empty() {
+            anyhow::bail!("Please provide a prompt");
+        }
+
+        let text = input::attach(
+            &input::combine(&prompt.join(" "), context.as_deref()),
+            &attachments,
+        );
+        Ok(ChatMessage::user(text).with_images(images))
+    }
+
+    fn toolbox(&self, profile: &config::Profile) -> Result<Toolbox> {
+        if self.tools.is_empty() {
+            Toolbox::configured(&profile.tools())
+        } else {
+            Toolbox::new(&self.tools)
+        }
+    }
+
+    /// Generation options from the flags, falling back to the environment
+    /// and the profile.
+    fn options(&self, profile: &config::Profile) -> Result<GenerationOptions> {
+        let options = GenerationOptions {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            max_tokens: self.max_tokens,
+            stop: self.stop.clone(),
+            seed: self.seed,
+            candidates: self.candidates,
+            thinking: self.think,
+        };
+        Ok(options.or(profile.generation_options()?))
+    }
+
+    /// How answers are received and shown.
+    fn delivery(&self, profile: &config::Profile) -> Result<Delivery> {
+        let continuations = match self.auto_continue {
+            Some(continuations) => continuations,
+            None => profile.auto_continue()?.unwrap_or(0),
+        };
+        Ok(Delivery {
+            format: self.output,
+            no_stream: self.no_stream,
+            continuations,
+            hide_thinking: self.hide_thinking,
+        })
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: self.connect_timeout,
+            read: self.read_timeout,
+            total: self.timeout,
+            stream_idle: self.stream_idle_timeout,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start an interactive multi-turn chat session
+    Chat,
+    /// Report estimated spend recorded in the local usage ledger
+    Usage {
+        /// Group the report by day, provider or model
+        #[arg(long, value_enum, default_value_t = ledger::GroupBy::Day)]
+        by: ledger::GroupBy,
+
+        /// Only include queries made on or after this date (YYYY-MM-DD)
+        #[arg(long, value_name = "DATE")]
+        since: Option<chrono::NaiveDate>,
+    },
+    /// Ask several models the same question at once and compare the answers
+    Compare {
+        /// The question or prompt to send to every model
+        #[arg(trailing_var_arg = true)]
+        prompt: Vec<String>,
+    },
+    /// Browse and replay previous queries
+    History {
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
+    /// Serve an OpenAI-compatible API on localhost, routing each request by
+    /// its model name
+    Serve {
+        /// Port to listen on
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+    },
+}
+
+#[derive(Subcommand)]
+enum HistoryCommand {
+    /// List the most recent queries
+    List {
+        /// Number of entries to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Find queries whose prompt or answer contains the text
+    Search {
+        /// Number of entries to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+
+        #[arg(required = true, trailing_var_arg = true)]
+        text: Vec<String>,
+    },
+    /// Print a query and its answer
+    Show { id: u64 },
+    /// Send a previous query again, optionally to another provider or model
+    Rerun { id: u64 },
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     let cli = Cli::parse();
+    let format = cli.output;
 
-    if cli.prompt.is_empty() {
-        eprintln!("Error: Please provide a prompt");
-        std::process::exit(1);
+    if let Err(e) = run(cli).await {
+        output::print_error(format, &e);
+        std::process::exit(output::provider_error(&e).map_or(1, exit_code));
     }
+}
 
-    let prompt = cli.prompt.join(" ");
+async fn run(cli: Cli) -> Result<()> {
+    let config = config::Config::load()?;
+    let profile = config.profile(cli.profile.as_deref())?;
+    let pricing = Pricing::new(&config.pricing);
+    let system = system_prompt(&cli, &profile)?;
+    let schema = cli.schema.as_deref().map(Schema::load).transpose()?;
+    let options = cli.options(&profile)?;
 
-    let provider = providers::get_provider()?;
+    if schema.is_some()
+        && matches!(
+            cli.command,
+            Some(Command::Chat | Command::Compare { .. } | Command::Serve { .. })
+        )
+    {
+        anyhow::bail!("--schema is only supported for single queries");
+    }
+    let attaches = !cli.files.is_empty() || !cli.images.is_empty();
+    if attaches && !matches!(cli.command, None | Some(Command::Compare { .. })) {
+        anyhow::bail!("--file and --image can only be used with a new prompt");
+    }
 
-    let system_message = String::from(
-        "You are a helpful assistant. You will receive a prompt and you will respond with a short, concise answer. If you respond with a code block, please format it using markdown syntax. Before the code block, please include a brief explanation of what the code does. Separate the explanation from the code block using --- dashes.",
-    );
+    if let Some(Command::Compare { prompt }) = &cli.command {
+        if cli.output != OutputFormat::Text {
+            anyhow::bail!("compare only supports text output");
+        }
+        let request = ChatRequest {
+            system,
+            messages: vec![cli.user_message(prompt, &profile)?],
+            options,
+            tools: Vec::new(),
+            schema: None,
+        };
+        let settings = config::Selection {
+            provider: None,
+            model: None,
+            max_retries: cli.max_retries,
+            timeouts: cli.timeouts(),
+        };
+        return compare::run(&profile, &cli.model, &settings, &request, &pricing).await;
+    }
 
-    let complete_prompt = format!("{} --- {}", system_message, prompt);
+    if let Some(Command::Serve { port }) = cli.command {
+        let settings = config::Selection {
+            provider: None,
+            model: None,
+            max_retries: cli.max_retries,
+            timeouts: cli.timeouts(),
+        };
+        return serve::run(config, profile, settings, options, pricing, port).await;
+    }
 
-    match provider.query(&complete_prompt).await {
-        Ok(response) => {
-            println!("{}", response.content);
+    let selection = cli.selection()?;
+    let toolbox = cli.toolbox(&profile)?;
 
-            let tokens_text = if let Some(tokens) = response.tokens_used {
-                format!("Tokens: {}", tokens)
-            } else {
-                "Tokens: N/A".to_string()
+    match &cli.command {
+        Some(Command::Chat) => {
+            let chain = provider_chain(&cli, &profile, &selection)?;
+            let request = ChatRequest {
+                system,
+                options,
+                tools: toolbox.definitions(),
+                ..Default::default()
+            };
+            let delivery = cli.delivery(&profile)?;
+            return chat::run(profile, chain, request, toolbox, &pricing, delivery).await;
+        }
+        Some(Command::Usage { by, since }) => return ledger::report(*by, *since),
+        Some(Command::History { command }) => {
+            return match command {
+                HistoryCommand::List { limit } => history::list(*limit),
+                HistoryCommand::Search { limit, text } => history::search(&text.join(" "), *limit),
+                HistoryCommand::Show { id } => history::show(*id),
+                HistoryCommand::Rerun { id } => {
+                    rerun(
+                        &cli,
+                        &profile,
+                        options,
+                        &toolbox,
+                        schema.as_ref(),
+                        &pricing,
+                        *id,
+                    )
+                    .await
+                }
             };
+        }
+        Some(Command::Compare { .. } | Command::Serve { .. }) | None => {}
+    }
 
-            let duration_text = format!("Time: {:.2}s", response.duration.as_secs_f64());
+    let request = ChatRequest {
+        system,
+        messages: vec![cli.user_message(&cli.prompt, &profile)?],
+        options,
+        tools: toolbox.definitions(),
+        schema: schema.as_ref().map(|schema| schema.value().clone()),
+    };
 
-            println!(
-                "\n[{} | {} | Model: {} | Provider: {}]",
-                tokens_text, duration_text, response.model, response.provider
-            );
-        }
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            std::process::exit(1);
+    query(
+        &provider_chain(&cli, &profile, &selection)?,
+        &request,
+        &toolbox,
+        schema.as_ref(),
+        cli.delivery(&profile)?,
+        &pricing,
+    )
+    .await
+}
+
+/// Re-sends a history entry with its original system prompt and messages.
+/// `--provider` and `--model` override the provider and model it used.
+async fn rerun(
+    cli: &Cli,
+    profile: &config::Profile,
+    options: GenerationOptions,
+    toolbox: &Toolbox,
+    schema: Option<&Schema>,
+    pricing: &Pricing,
+    id: u64,
+) -> Result<()> {
+    let entry = history::find(id)?;
+    if entry.images > 0 {
+        eprintln!("Warning: images are not kept in the history; sending the query without them");
+    }
+
+    let selection = config::Selection {
+        model: cli
+            .model()?
+            .or_else(|| cli.provider.is_none().then(|| entry.model.clone())),
+        provider: cli.provider.clone().or(Some(entry.provider)),
+        ..cli.selection()?
+    };
+
+    let request = ChatRequest {
+        system: entry.system,
+        messages: entry.messages,
+        options,
+        tools: toolbox.definitions(),
+        schema: schema.map(|schema| schema.value().clone()),
+    };
+
+    query(
+        &provider_chain(cli, profile, &selection)?,
+        &request,
+        toolbox,
+        schema,
+        cli.delivery(profile)?,
+        pricing,
+    )
+    .await
+}
+
+/// The selected provider and its fallbacks, unless `--no-fallback` is given.
+fn provider_chain(
+    cli: &Cli,
+    profile: &config::Profile,
+    selection: &config::Selection,
+) -> Result<Vec<providers::ProviderConfig>> {
+    if cli.no_fallback {
+        Ok(vec![profile.provider_config(selection)?])
+    } else {
+        profile.provider_chain(selection)
+    }
+}
+
+/// Sends the request along the provider chain, running any tools the model
+/// calls and validating the answer against the schema, prints the answer with
+/// its metadata and records it in the usage ledger and the history.
+async fn query(
+    chain: &[providers::ProviderConfig],
+    request: &ChatRequest,
+    toolbox: &Toolbox,
+    schema: Option<&Schema>,
+    delivery: Delivery,
+    pricing: &Pricing,
+) -> Result<()> {
+    let provider = providers::get_provider_chain(chain)?;
+
+    // The history keeps the prompt rather than the tool turns, so a rerun
+    // calls the tools again.
+    let mut conversation = request.clone();
+    let response = match schema {
+        Some(schema) => {
+            schema::complete(
+                provider.as_ref(),
+                &mut conversation,
+                toolbox,
+                schema,
+                delivery,
+            )
+            .await
         }
+        None => toolbox::complete(provider.as_ref(), &mut conversation, toolbox, delivery).await,
     }
+    .map_err(|e| input::image_hint(e, request))?;
+    let cost = pricing.cost(&response);
+    output::print_summary(delivery.format, &response, cost)?;
+    ledger::record(&response, cost);
+    history::record(&chain[response.failures.len()].provider, request, &response);
 
     Ok(())
 }
+
+/// Resolves the system prompt from `--system`, `--system-file`,
+/// `CLM_SYSTEM_PROMPT`, the profile and the built-in default, in that order.
+/// An empty prompt disables the system prompt entirely.
+fn system_prompt(cli: &Cli, profile: &config::Profile) -> Result<Option<String>> {
+    let prompt = if let Some(system) = &cli.system {
+        system.clone()
+    } else if let Some(path) = &cli.system_file {
+        std::fs::read_to_string(path).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to read system prompt from {}: {}",
+                path.display(),
+                e
+            )
+        })?
+    } else if let Some(system) = profile.system_prompt() {
+        system
+    } else {
+        DEFAULT_SYSTEM_PROMPT.to_string()
+    };
+
+    let prompt = prompt.trim();
+    Ok((!prompt.is_empty()).then(|| prompt.to_string()))
+}
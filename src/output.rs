@@ -0,0 +1,665 @@
+use crate::config;
+use crate::pricing::format_cost;
+use anyhow::Result;
+use clm::providers::{
+    AiProvider, AiResponse, ChatMessage, ChatRequest, Chunk, FinishReason, ProviderFailure,
+    Segment, ToolCall, Usage,
+};
+use serde::Serialize;
+use std::io::{IsTerminal, Write};
+
+/// Version of the JSON documents emitted by `--output json|ndjson`. Bump it
+/// whenever a field is renamed or removed.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Sent after an answer that hit the token limit to have the model go on.
+const CONTINUE_PROMPT: &str =
+    "Continue exactly where your answer stopped, without repeating anything.";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable answer followed by a metadata footer
+    #[default]
+    Text,
+    /// A single JSON document once the answer is complete
+    Json,
+    /// One JSON event per line: text chunks while streaming, then the response
+    Ndjson,
+}
+
+/// How an answer is received and shown.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Delivery {
+    pub format: OutputFormat,
+    /// Wait for the full answer instead of streaming it.
+    pub no_stream: bool,
+    /// Follow-up requests allowed to continue an answer cut off at the token
+    /// limit.
+    pub continuations: u32,
+    /// Leave the model's reasoning out of text output.
+    pub hide_thinking: bool,
+}
+
+#[derive(Serialize)]
+pub struct ResponseDocument<'a> {
+    pub version: u32,
+    pub content: &'a str,
+    /// Text of the other candidates asked for with `--n`.
+    pub alternatives: &'a [String],
+    /// The text, reasoning and tool calls in the order they were produced.
+    pub segments: Vec<SegmentDocument<'a>>,
+    pub model: &'a str,
+    pub provider: &'a str,
+    pub duration_ms: u64,
+    pub usage: UsageDocument,
+    /// Estimated cost in USD, `None` when the model's price is unknown.
+    pub cost_usd: Option<f64>,
+    pub retries: u32,
+    /// Fallback providers that failed before `provider` answered.
+    pub failures: &'a [ProviderFailure],
+    pub finish_reason: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SegmentDocument<'a> {
+    Text { text: &'a str },
+    Thinking { text: &'a str },
+    ToolUse(&'a ToolCall),
+}
+
+impl<'a> From<&'a Segment> for SegmentDocument<'a> {
+    fn from(segment: &'a Segment) -> Self {
+        match segment {
+            Segment::Text(text) => SegmentDocument::Text { text },
+            Segment::Thinking(text) => SegmentDocument::Thinking { text },
+            Segment::ToolUse(call) => SegmentDocument::ToolUse(call),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct UsageDocument {
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
+    pub total_tokens: Option<u32>,
+    pub cached_tokens: Option<u32>,
+    pub reasoning_tokens: Option<u32>,
+}
+
+impl<'a> ResponseDocument<'a> {
+    pub fn new(response: &'a AiResponse, cost: Option<f64>) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            content: &response.content,
+            alternatives: &response.alternatives,
+            segments: response.segments.iter().map(Into::into).collect(),
+            model: &response.model,
+            provider: &response.provider,
+            duration_ms: response.duration.as_millis() as u64,
+            usage: UsageDocument {
+                input_tokens: response.usage.input_tokens,
+                output_tokens: response.usage.output_tokens,
+                total_tokens: response.usage.total_tokens(),
+                cached_tokens: response.usage.cached_tokens,
+                reasoning_tokens: response.usage.reasoning_tokens,
+            },
+            cost_usd: cost,
+            retries: response.retries,
+            failures: &response.failures,
+            finish_reason: response.finish_reason.as_ref().map(FinishReason::as_str),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event<'a> {
+    Chunk { version: u32, content: &'a str },
+    Thinking { version: u32, content: &'a str },
+    Response(ResponseDocument<'a>),
+}
+
+#[derive(Serialize)]
+struct ErrorDocument<'a> {
+    version: u32,
+    error: ErrorBody<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    message: String,
+    /// `Error::kind` of a provider error, else `other`.
+    kind: &'a str,
+    status: Option<u16>,
+    /// The provider's error response, unchanged.
+    body: Option<&'a str>,
+}
+
+/// Sends `request` to the provider and prints the answer as `delivery` asks.
+/// Text and NDJSON output stream as the answer arrives unless `no_stream` is
+/// set. An answer cut off at the token limit is continued with up to
+/// `continuations` follow-up requests, printed as one answer. Alternative
+/// answers are never streamed. The metadata is printed separately by
+/// `print_summary`.
+pub async fn send(
+    provider: &(dyn AiProvider + Send + Sync),
+    request: &ChatRequest,
+    delivery: Delivery,
+) -> Result<AiResponse> {
+    let delivery = Delivery {
+        no_stream: delivery.no_stream || request.options.candidates.is_some_and(|n| n > 1),
+        ..delivery
+    };
+    let mut response = receive(provider, request, delivery).await?;
+
+    let mut conversation: Option<ChatRequest> = None;
+    let mut part = response.content.clone();
+
+    for _ in 0..delivery.continuations {
+        if !was_cut_off(&response) {
+            break;
+        }
+
+        let conversation = conversation.get_or_insert_with(|| request.clone());
+        conversation.messages.push(ChatMessage::assistant(part));
+        conversation
+            .messages
+            .push(ChatMessage::user(CONTINUE_PROMPT));
+
+        let next = receive(provider, conversation, delivery).await?;
+        part = next.content.clone();
+        response = merge(response, next);
+    }
+
+    match delivery.format {
+        OutputFormat::Text if delivery.no_stream => {
+            if !delivery.hide_thinking {
+                print_thinking(&response.segments);
+            }
+            print_answer(&response);
+        }
+        OutputFormat::Text => println!(),
+        OutputFormat::Json | OutputFormat::Ndjson => {}
+    }
+    print_warnings(&response);
+    warn_incomplete(&response);
+
+    Ok(response)
+}
+
+/// Whether the answer stopped at the token limit and can be continued.
+fn was_cut_off(response: &AiResponse) -> bool {
+    response.finish_reason == Some(FinishReason::Length) && response.tool_calls.is_empty()
+}
+
+/// Joins a continuation onto the answer so far. Text, usage, time, retries
+/// and warnings add up; the finish reason and the rest come from `next`.
+fn merge(response: AiResponse, next: AiResponse) -> AiResponse {
+    let mut segments = response.segments;
+    for segment in next.segments {
+        Segment::push(&mut segments, segment);
+    }
+
+    AiResponse {
+        content: response.content + &next.content,
+        segments,
+        alternatives: response.alternatives,
+        warnings: [response.warnings, next.warnings].concat(),
+        usage: next.usage.add(&response.usage),
+        duration: response.duration + next.duration,
+        retries: response.retries + next.retries,
+        ..next
+    }
+}
+
+/// Prints the answer, or each candidate under a numbered heading when
+/// alternatives were asked for.
+pub fn print_answer(response: &AiResponse) {
+    if response.alternatives.is_empty() {
+        println!("{}", response.content);
+        return;
+    }
+
+    let answers = std::iter::once(&response.content).chain(&response.alternatives);
+    for (index, answer) in answers.enumerate() {
+        if index > 0 {
+            println!();
+        }
+        println!("--- Answer {} ---\n{}", index + 1, answer);
+    }
+}
+
+/// Prints the reasoning segments of a complete answer.
+fn print_thinking(segments: &[Segment]) {
+    let mut view = ThinkingView::default();
+    for segment in segments {
+        if let Segment::Thinking(text) = segment {
+            view.write(text);
+        }
+    }
+    view.close();
+}
+
+/// Sends one request, streaming the text or NDJSON chunks as they arrive.
+async fn receive(
+    provider: &(dyn AiProvider + Send + Sync),
+    request: &ChatRequest,
+    delivery: Delivery,
+) -> Result<AiResponse> {
+    let result = match delivery.format {
+        OutputFormat::Text if !delivery.no_stream => {
+            let mut stdout = std::io::stdout();
+            let mut view = ThinkingView::default();
+            let mut on_chunk = |chunk: Chunk| match chunk {
+                Chunk::Text(text) => {
+                    view.close();
+                    let _ = stdout.write_all(text.as_bytes());
+                    let _ = stdout.flush();
+                }
+                Chunk::Thinking(text) if !delivery.hide_thinking => view.write(text),
+                Chunk::Thinking(_) => {}
+            };
+            let result = provider.query_stream(request, &mut on_chunk).await;
+            view.close();
+            if result.is_err() {
+                println!();
+            }
+            result
+        }
+        OutputFormat::Ndjson if !delivery.no_stream => {
+            let mut on_chunk = |chunk: Chunk| {
+                print_line(&match chunk {
+                    Chunk::Text(content) => Event::Chunk {
+                        version: SCHEMA_VERSION,
+                        content,
+                    },
+                    Chunk::Thinking(content) => Event::Thinking {
+                        version: SCHEMA_VERSION,
+                        content,
+                    },
+                });
+            };
+            provider.query_stream(request, &mut on_chunk).await
+        }
+        _ => provider.query(request).await,
+    };
+    Ok(result?)
+}
+
+/// Shows reasoning on stderr under a `[Thinking]` heading, dimmed on a
+/// terminal, so it stays apart from the answer on stdout.
+#[derive(Default)]
+struct ThinkingView {
+    open: bool,
+}
+
+impl ThinkingView {
+    fn write(&mut self, text: &str) {
+        let mut stderr = std::io::stderr();
+        let dim = stderr.is_terminal();
+        if !self.open {
+            self.open = true;
+            let _ = writeln!(stderr, "[Thinking]");
+        }
+        let _ = if dim {
+            write!(stderr, "\x1b[2m{}\x1b[0m", text)
+        } else {
+            write!(stderr, "{}", text)
+        };
+    }
+
+    /// Ends the section before the answer starts.
+    fn close(&mut self) {
+        if self.open {
+            self.open = false;
+            eprintln!("\n");
+        }
+    }
+}
+
+/// Prints the warnings the provider reported, e.g. retries, on stderr.
+pub fn print_warnings(response: &AiResponse) {
+    for warning in &response.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+}
+
+/// Warns on stderr when the answer was cut off or withheld.
+pub fn warn_incomplete(response: &AiResponse) {
+    match response.finish_reason {
+        Some(FinishReason::Length) => eprintln!(
+            "Warning: The answer was cut off at the output token limit. Raise it with --max-tokens or use --auto-continue."
+        ),
+        Some(FinishReason::ContentFilter) => eprintln!(
+            "Warning: The answer was stopped by {} for safety reasons.",
+            response.provider
+        ),
+        _ => {}
+    }
+}
+
+/// Prints the response metadata: the footer for text output, the full
+/// document for JSON and the final event for NDJSON.
+pub fn print_summary(format: OutputFormat, response: &AiResponse, cost: Option<f64>) -> Result<()> {
+    let document = ResponseDocument::new(response, cost);
+
+    match format {
+        OutputFormat::Text => print_footer(response, cost),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&document)?),
+        OutputFormat::Ndjson => print_line(&Event::Response(document)),
+    }
+
+    Ok(())
+}
+
+pub fn print_footer(response: &AiResponse, cost: Option<f64>) {
+    let tokens_text = format_usage(&response.usage);
+
+    let duration_text = format!("Time: {:.2}s", response.duration.as_secs_f64());
+
+    let cost_text = match cost {
+        Some(cost) => format!(" | Cost: {}", format_cost(cost)),
+        None => String::new(),
+    };
+
+    let retries_text = match response.retries {
+        0 => String::new(),
+        1 => " | 1 retry".to_string(),
+        retries => format!(" | {} retries", retries),
+    };
+
+    let finish_text = match &response.finish_reason {
+        Some(reason) => format!(" | Finish: {}", reason.as_str()),
+        None => String::new(),
+    };
+
+    let fallback_text = if response.failures.is_empty() {
+        String::new()
+    } else {
+        let failed: Vec<&str> = response
+            .failures
+            .iter()
+            .map(|failure| failure.provider.as_str())
+            .collect();
+        format!(" (fell back from {})", failed.join(", "))
+    };
+
+    println!(
+        "\n[{}{} | {}{}{} | Model: {} | Provider: {}{}]",
+        tokens_text,
+        cost_text,
+        duration_text,
+        retries_text,
+        finish_text,
+        response.model,
+        response.provider,
+        fallback_text
+    );
+}
+
+/// Formats token usage as e.g. `Tokens: 19 (12 in, 7 out, 4 cached)`,
+/// leaving out the counts the provider did not report.
+fn format_usage(usage: &Usage) -> String {
+    let Some(total) = usage.total_tokens() else {
+        return "Tokens: N/A".to_string();
+    };
+
+    let details: Vec<String> = [
+        (usage.input_tokens, "in"),
+        (usage.output_tokens, "out"),
+        (usage.cached_tokens, "cached"),
+        (usage.reasoning_tokens, "reasoning"),
+    ]
+    .into_iter()
+    .filter_map(|(count, label)| count.map(|count| format!("{} {}", count, label)))
+    .collect();
+
+    format!("Tokens: {} ({})", total, details.join(", "))
+}
+
+/// Prints an error and its causes to stderr, as a JSON document for the
+/// machine-readable formats.
+pub fn print_error(format: OutputFormat, error: &anyhow::Error) {
+    let provider_error = provider_error(error);
+    let api_error = provider_error.and_then(clm::Error::api_error);
+
+    match format {
+        OutputFormat::Text => {
+            eprintln!("Error: {:#}", error);
+            if let Some(hint) = provider_error.and_then(hint) {
+                eprintln!("Hint: {}", hint);
+            }
+        }
+        OutputFormat::Json | OutputFormat::Ndjson => {
+            let document = ErrorDocument {
+                version: SCHEMA_VERSION,
+                error: ErrorBody {
+                    message: format!("{:#}", error),
+                    kind: provider_error.map_or("other", clm::Error::kind),
+                    status: api_error.map(|e| e.status),
+                    body: api_error.map(|e| e.body.as_str()),
+                },
+            };
+            eprintln!(
+                "{}",
+                serde_json::to_string(&document).unwrap_or_else(|_| error.to_string())
+            );
+        }
+    }
+}
+
+/// The provider error behind `error`, if a provider failed.
+pub fn provider_error(error: &anyhow::Error) -> Option<&clm::Error> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<clm::Error>())
+}
+
+/// What the user can do about a provider error.
+fn hint(error: &clm::Error) -> Option<String> {
+    let hint = match error.root() {
+        clm::Error::Missing { provider, setting } => {
+            return Some(config::missing_hint(provider, *setting));
+        }
+        clm::Error::Auth(_) => "Check that the API key is valid and can use this model",
+        clm::Error::RateLimit(_) => {
+            "The provider is limiting requests; wait a moment, raise --max-retries or set up fallback providers"
+        }
+        clm::Error::Server(_) => {
+            "The provider is having problems; try again later or set up fallback providers"
+        }
+        clm::Error::Network(_) => "Check the network connection and the provider's base URL",
+        clm::Error::Timeout(_) => {
+            "Raise the limit with --timeout, --read-timeout or --stream-idle-timeout"
+        }
+        _ => return None,
+    };
+    Some(hint.to_string())
+}
+
+fn print_line(event: &Event) {
+    let mut stdout = std::io::stdout();
+    if let Ok(line) = serde_json::to_string(event) {
+        let _ = writeln!(stdout, "{}", line);
+        let _ = stdout.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn answer(content: &str, finish_reason: FinishReason, output_tokens: u32) -> AiResponse {
+        AiResponse {
+            content: content.to_string(),
+            tool_calls: Vec::new(),
+            segments: vec![Segment::Text(content.to_string())],
+            alternatives: Vec::new(),
+            reasoning: Vec::new(),
+            finish_reason: Some(finish_reason),
+            usage: Usage {
+                input_tokens: Some(10),
+                output_tokens: Some(output_tokens),
+                ..Default::default()
+            },
+            duration: Duration::from_millis(100),
+            model: "test-model".to_string(),
+            provider: "test".to_string(),
+            retries: 0,
+            failures: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Answers with the given responses in order and records the requests.
+    struct Scripted {
+        answers: Mutex<Vec<AiResponse>>,
+        requests: Mutex<Vec<ChatRequest>>,
+    }
+
+    impl Scripted {
+        fn new(answers: Vec<AiResponse>) -> Self {
+            Self {
+                answers: Mutex::new(answers),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl AiProvider for Scripted {
+        async fn query(&self, request: &ChatRequest) -> clm::Result<AiResponse> {
+            self.requests.lock().unwrap().push(request.clone());
+            Ok(self.answers.lock().unwrap().remove(0))
+        }
+
+        async fn query_stream(
+            &self,
+            request: &ChatRequest,
+            _: &mut clm::providers::ChunkHandler<'_>,
+        ) -> clm::Result<AiResponse> {
+            self.query(request).await
+        }
+    }
+
+    fn quiet(continuations: u32) -> Delivery {
+        Delivery {
+            format: OutputFormat::Json,
+            no_stream: true,
+            continuations,
+            hide_thinking: false,
+        }
+    }
+
+    fn prompt() -> ChatRequest {
+        ChatRequest {
+            messages: vec![ChatMessage::user("Write a poem")],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_joins_text_and_adds_up_usage() {
+        let mut first = answer("Roses are ", FinishReason::Length, 4);
+        first.retries = 1;
+        first.warnings = vec!["retried".to_string()];
+        let mut next = answer("red.", FinishReason::Stop, 2);
+        next.segments
+            .insert(0, Segment::Thinking("Rhyme.".to_string()));
+        next.warnings = vec!["top_k ignored".to_string()];
+
+        let merged = merge(first, next);
+        assert_eq!(merged.content, "Roses are red.");
+        assert_eq!(
+            merged.segments,
+            [
+                Segment::Text("Roses are ".to_string()),
+                Segment::Thinking("Rhyme.".to_string()),
+                Segment::Text("red.".to_string()),
+            ]
+        );
+        assert_eq!(merged.usage.input_tokens, Some(20));
+        assert_eq!(merged.usage.output_tokens, Some(6));
+        assert_eq!(merged.duration, Duration::from_millis(200));
+        assert_eq!(merged.retries, 1);
+        assert_eq!(merged.warnings, ["retried", "top_k ignored"]);
+        assert_eq!(merged.finish_reason, Some(FinishReason::Stop));
+    }
+
+    #[test]
+    fn merge_runs_text_segments_together() {
+        let merged = merge(
+            answer("Roses ", FinishReason::Length, 1),
+            answer("are red.", FinishReason::Stop, 2),
+        );
+        assert_eq!(
+            merged.segments,
+            [Segment::Text("Roses are red.".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn continues_until_the_answer_is_complete() {
+        let provider = Scripted::new(vec![
+            answer("Roses ", FinishReason::Length, 1),
+            answer("are ", FinishReason::Length, 1),
+            answer("red.", FinishReason::Stop, 1),
+        ]);
+
+        let response = send(&provider, &prompt(), quiet(5)).await.unwrap();
+        assert_eq!(response.content, "Roses are red.");
+        assert_eq!(response.usage.output_tokens, Some(3));
+        assert_eq!(response.finish_reason, Some(FinishReason::Stop));
+
+        let requests = provider.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let contents: Vec<&str> = requests[2]
+            .messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                "Write a poem",
+                "Roses ",
+                CONTINUE_PROMPT,
+                "are ",
+                CONTINUE_PROMPT
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_continuation_limit() {
+        let provider = Scripted::new(vec![
+            answer("a", FinishReason::Length, 1),
+            answer("b", FinishReason::Length, 1),
+            answer("c", FinishReason::Length, 1),
+            answer("d", FinishReason::Stop, 1),
+        ]);
+
+        let response = send(&provider, &prompt(), quiet(2)).await.unwrap();
+        assert_eq!(response.content, "abc");
+        assert_eq!(response.finish_reason, Some(FinishReason::Length));
+        assert_eq!(provider.requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn no_continuation_without_a_cut_off_or_with_tool_calls() {
+        let provider = Scripted::new(vec![answer("Done.", FinishReason::Stop, 1)]);
+        send(&provider, &prompt(), quiet(3)).await.unwrap();
+        assert_eq!(provider.requests.lock().unwrap().len(), 1);
+
+        let mut calling = answer("", FinishReason::Length, 1);
+        calling.tool_calls = vec![ToolCall::new(
+            "call_1".to_string(),
+            "current_time".to_string(),
+            "{}",
+        )];
+        assert!(!was_cut_off(&calling));
+    }
+}
@@ -0,0 +1,217 @@
+use clm::providers::{AiResponse, Usage};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Token prices in USD per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+    /// Price of input tokens served from the prompt cache. Defaults to the
+    /// regular input price.
+    pub cached_input: Option<f64>,
+}
+
+impl Price {
+    const fn new(input: f64, output: f64, cached_input: f64) -> Self {
+        Self {
+            input,
+            output,
+            cached_input: Some(cached_input),
+        }
+    }
+
+    /// Estimated cost of `usage` in USD, or `None` when the provider reported
+    /// no token counts.
+    pub fn cost(&self, usage: &Usage) -> Option<f64> {
+        usage.total_tokens()?;
+
+        let input = usage.input_tokens.unwrap_or(0);
+        let cached = usage.cached_tokens.unwrap_or(0).min(input);
+        let output = usage.output_tokens.unwrap_or(0);
+
+        let cost = f64::from(input - cached) * self.input
+            + f64::from(cached) * self.cached_input.unwrap_or(self.input)
+            + f64::from(output) * self.output;
+
+        Some(cost / 1_000_000.0)
+    }
+}
+
+/// Bundled list prices, keyed by `provider:model`. A key also matches the
+/// model's dated releases, so `claude-sonnet-4` covers
+/// `claude-sonnet-4-20250514` but not `claude-sonnet-4-5`. A key of just
+/// `provider:` matches all of the provider's models.
+const DEFAULT_PRICES: &[(&str, Price)] = &[
+    ("openai:gpt-5", Price::new(1.25, 10.0, 0.125)),
+    ("openai:gpt-5-pro", Price::new(15.0, 120.0, 15.0)),
+    ("openai:gpt-5-mini", Price::new(0.25, 2.0, 0.025)),
+    ("openai:gpt-5-nano", Price::new(0.05, 0.4, 0.005)),
+    ("openai:gpt-4.1", Price::new(2.0, 8.0, 0.5)),
+    ("openai:gpt-4.1-mini", Price::new(0.4, 1.6, 0.1)),
+    ("openai:gpt-4.1-nano", Price::new(0.1, 0.4, 0.025)),
+    ("openai:gpt-4o", Price::new(2.5, 10.0, 1.25)),
+    ("openai:gpt-4o-mini", Price::new(0.15, 0.6, 0.075)),
+    ("openai:o3", Price::new(2.0, 8.0, 0.5)),
+    ("openai:o3-pro", Price::new(20.0, 80.0, 20.0)),
+    ("openai:o3-mini", Price::new(1.1, 4.4, 0.55)),
+    ("openai:o4-mini", Price::new(1.1, 4.4, 0.275)),
+    ("anthropic:claude-opus-4", Price::new(15.0, 75.0, 1.5)),
+    ("anthropic:claude-opus-4-1", Price::new(15.0, 75.0, 1.5)),
+    ("anthropic:claude-opus-4-5", Price::new(5.0, 25.0, 0.5)),
+    ("anthropic:claude-sonnet-4", Price::new(3.0, 15.0, 0.3)),
+    ("anthropic:claude-sonnet-4-5", Price::new(3.0, 15.0, 0.3)),
+    ("anthropic:claude-haiku-4-5", Price::new(1.0, 5.0, 0.1)),
+    ("anthropic:claude-3-7-sonnet", Price::new(3.0, 15.0, 0.3)),
+    ("anthropic:claude-3-5-haiku", Price::new(0.8, 4.0, 0.08)),
+    ("google:gemini-2.5-pro", Price::new(1.25, 10.0, 0.31)),
+    ("google:gemini-2.5-flash", Price::new(0.3, 2.5, 0.075)),
+    ("google:gemini-2.5-flash-lite", Price::new(0.1, 0.4, 0.025)),
+    ("google:gemini-2.0-flash", Price::new(0.1, 0.4, 0.025)),
+    ("ollama:", Price::new(0.0, 0.0, 0.0)),
+];
+
+/// Looks up prices for a provider and model, preferring the `[pricing]`
+/// table from the config file over the bundled defaults.
+#[derive(Debug, Clone, Default)]
+pub struct Pricing {
+    overrides: BTreeMap<String, Price>,
+}
+
+impl Pricing {
+    pub fn new(overrides: &BTreeMap<String, Price>) -> Self {
+        Self {
+            overrides: overrides
+                .iter()
+                .map(|(key, price)| (key.to_lowercase(), *price))
+                .collect(),
+        }
+    }
+
+    /// Models routed through OpenRouter, such as `anthropic/claude-sonnet-4`,
+    /// fall back to the upstream provider's price.
+    pub fn price(&self, provider: &str, model: &str) -> Option<Price> {
+        self.lookup(provider, model).or_else(|| {
+            let (vendor, model) = model.split_once('/')?;
+            self.lookup(vendor, model)
+        })
+    }
+
+    pub fn cost(&self, response: &AiResponse) -> Option<f64> {
+        self.price(&response.provider, &response.model)?
+            .cost(&response.usage)
+    }
+
+    fn lookup(&self, provider: &str, model: &str) -> Option<Price> {
+        let key = format!("{}:{}", provider, model).to_lowercase();
+
+        // Overrides come last so they win over a bundled key of equal length.
+        DEFAULT_PRICES
+            .iter()
+            .map(|(prefix, price)| (*prefix, price))
+            .chain(
+                self.overrides
+                    .iter()
+                    .map(|(prefix, price)| (prefix.as_str(), price)),
+            )
+            .filter(|(prefix, _)| matches(prefix, &key))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, price)| *price)
+    }
+}
+
+/// Whether a price key applies to `key`: the same model, a release of it
+/// dated `-YYYY...`, or any model for a bare `provider:` key.
+fn matches(prefix: &str, key: &str) -> bool {
+    let Some(rest) = key.strip_prefix(prefix) else {
+        return false;
+    };
+
+    prefix.ends_with(':')
+        || rest.is_empty()
+        || rest
+            .strip_prefix('-')
+            .and_then(|date| date.get(..4))
+            .is_some_and(|year| year.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Formats a cost in USD with enough precision for single queries.
+pub fn format_cost(cost: f64) -> String {
+    if cost == 0.0 || cost >= 0.01 {
+        format!("${:.2}", cost)
+    } else {
+        format!("${:.6}", cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input_price(provider: &str, model: &str) -> Option<f64> {
+        Pricing::default()
+            .price(provider, model)
+            .map(|price| price.input)
+    }
+
+    #[test]
+    fn exact_model() {
+        assert_eq!(input_price("openai", "gpt-4.1-mini"), Some(0.4));
+        assert_eq!(input_price("openai", "o3"), Some(2.0));
+    }
+
+    #[test]
+    fn dated_releases() {
+        assert_eq!(
+            input_price("anthropic", "claude-sonnet-4-20250514"),
+            Some(3.0)
+        );
+        assert_eq!(input_price("openai", "gpt-4.1-2025-04-14"), Some(2.0));
+    }
+
+    #[test]
+    fn longer_names_are_other_models() {
+        assert_eq!(input_price("openai", "o3-pro"), Some(20.0));
+        assert_eq!(input_price("openai", "gpt-5-pro"), Some(15.0));
+        assert_eq!(input_price("anthropic", "claude-opus-4-5"), Some(5.0));
+        assert_eq!(
+            input_price("anthropic", "claude-opus-4-5-20251101"),
+            Some(5.0)
+        );
+        assert_eq!(input_price("openai", "o3-deep-research"), None);
+        assert_eq!(input_price("openai", "gpt-4.1x"), None);
+    }
+
+    #[test]
+    fn provider_keys_match_every_model() {
+        assert_eq!(input_price("ollama", "llama3.2:3b"), Some(0.0));
+    }
+
+    #[test]
+    fn openrouter_uses_upstream_price() {
+        assert_eq!(
+            input_price("openrouter", "anthropic/claude-sonnet-4"),
+            Some(3.0)
+        );
+        assert_eq!(input_price("openrouter", "openai/o3-pro"), Some(20.0));
+    }
+
+    #[test]
+    fn overrides_win() {
+        let overrides = BTreeMap::from([(
+            "OpenAI:o3".to_string(),
+            Price {
+                input: 1.0,
+                output: 4.0,
+                cached_input: None,
+            },
+        )]);
+        let pricing = Pricing::new(&overrides);
+        assert_eq!(pricing.price("openai", "o3").map(|p| p.input), Some(1.0));
+        assert_eq!(
+            pricing.price("openai", "o3-pro").map(|p| p.input),
+            Some(20.0)
+        );
+    }
+}
@@ -1,117 +1,827 @@
-use crate::providers::{AiProvider, AiResponse};
-use anyhow::Result;
-use reqwest::Client;
+use crate::providers::stream::{read_lines, sse_data};
+use crate::providers::tools::ToolCallStream;
+use crate::providers::{
+    self, AiProvider, AiResponse, ChatMessage, ChatRequest, Chunk, ChunkHandler, FinishReason,
+    ProviderBuilder, ProviderConfig, ReasoningBlock, Role, Segment, Thinking, ToolDefinition,
+};
+use crate::providers::{http, retry};
+use crate::{Error, Result, Setting};
+use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize)]
 struct AnthropicRequest {
     model: String,
     max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
     messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<ThinkingConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    stream: bool,
+}
+
+/// Extended thinking. The budget counts towards `max_tokens`.
+#[derive(Serialize)]
+struct ThinkingConfig {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    budget_tokens: u32,
 }
 
 #[derive(Serialize)]
 struct Message {
     role: String,
-    content: String,
+    content: Vec<Block>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Block {
+    Thinking {
+        thinking: String,
+        signature: String,
+    },
+    RedactedThinking {
+        data: String,
+    },
+    Text {
+        text: String,
+    },
+    Image {
+        source: ImageSource,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+    },
+}
+
+#[derive(Serialize)]
+struct ImageSource {
+    #[serde(rename = "type")]
+    kind: String,
+    media_type: String,
+    data: String,
+}
+
+impl From<&ReasoningBlock> for Block {
+    fn from(block: &ReasoningBlock) -> Self {
+        match block.clone() {
+            ReasoningBlock::Thinking {
+                thinking,
+                signature,
+            } => Block::Thinking {
+                thinking,
+                signature,
+            },
+            ReasoningBlock::Redacted { data } => Block::RedactedThinking { data },
+        }
+    }
+}
+
+/// Tool results are sent as user messages, and consecutive messages with the
+/// same role are merged so that all results of a turn arrive together. With
+/// extended thinking, an assistant turn that calls tools must start with the
+/// thinking blocks it was answered with.
+fn messages(messages: &[ChatMessage]) -> Vec<Message> {
+    let mut result: Vec<Message> = Vec::new();
+
+    for message in messages {
+        let (role, blocks) = match message.role {
+            Role::Tool => (
+                "user",
+                vec![Block::ToolResult {
+                    tool_use_id: message.tool_call_id.clone().unwrap_or_default(),
+                    content: message.content.clone(),
+                }],
+            ),
+            role => {
+                let images = message.images.iter().map(|image| Block::Image {
+                    source: ImageSource {
+                        kind: "base64".to_string(),
+                        media_type: image.mime_type.clone(),
+                        data: image.data.clone(),
+                    },
+                });
+                // Empty text blocks are rejected, e.g. before a tool call.
+                let text = (!message.content.is_empty()).then(|| Block::Text {
+                    text: message.content.clone(),
+                });
+                let calls = message.tool_calls.iter().map(|call| Block::ToolUse {
+                    id: call.id.clone(),
+                    name: call.name.clone(),
+                    input: call.arguments.clone(),
+                });
+                let reasoning = message.reasoning.iter().map(Block::from);
+                let blocks = reasoning.chain(images).chain(text).chain(calls);
+                (role.as_str(), blocks.collect())
+            }
+        };
+
+        match result.last_mut() {
+            Some(last) if last.role == role => last.content.extend(blocks),
+            _ => result.push(Message {
+                role: role.to_string(),
+                content: blocks,
+            }),
+        }
+    }
+
+    result
+}
+
+#[derive(Serialize)]
+struct Tool {
+    name: String,
+    description: String,
+    input_schema: serde_json::Value,
+}
+
+impl From<&ToolDefinition> for Tool {
+    fn from(tool: &ToolDefinition) -> Self {
+        Self {
+            name: tool.name.clone(),
+            description: tool.description.clone(),
+            input_schema: tool.parameters.clone(),
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct AnthropicResponse {
     content: Vec<ContentBlock>,
+    stop_reason: Option<String>,
     usage: Usage,
 }
 
 #[derive(Deserialize)]
-struct ContentBlock {
-    text: String,
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Thinking {
+        thinking: String,
+        #[serde(default)]
+        signature: String,
+    },
+    RedactedThinking {
+        data: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    #[serde(other)]
+    Other,
 }
 
 #[derive(Deserialize)]
 struct Usage {
     input_tokens: u32,
     output_tokens: u32,
+    cache_creation_input_tokens: Option<u32>,
+    cache_read_input_tokens: Option<u32>,
+}
+
+impl From<Usage> for providers::Usage {
+    fn from(usage: Usage) -> Self {
+        // Anthropic reports cached input separately from `input_tokens`.
+        let cache_creation = usage.cache_creation_input_tokens.unwrap_or(0);
+        let cache_read = usage.cache_read_input_tokens.unwrap_or(0);
+
+        Self {
+            input_tokens: Some(usage.input_tokens + cache_creation + cache_read),
+            output_tokens: Some(usage.output_tokens),
+            cached_tokens: usage.cache_read_input_tokens,
+            reasoning_tokens: None,
+        }
+    }
 }
 
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: StreamMessage,
+    },
+    ContentBlockStart {
+        index: usize,
+        content_block: StartBlock,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: StreamDelta,
+    },
+    MessageDelta {
+        #[serde(default)]
+        delta: MessageDelta,
+        usage: DeltaUsage,
+    },
+    Error {
+        error: StreamError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct StreamMessage {
+    usage: Usage,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StartBlock {
+    ToolUse {
+        id: String,
+        name: String,
+    },
+    RedactedThinking {
+        data: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamDelta {
+    TextDelta {
+        text: String,
+    },
+    ThinkingDelta {
+        thinking: String,
+    },
+    /// Ends a thinking block.
+    SignatureDelta {
+        signature: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Default)]
+struct MessageDelta {
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DeltaUsage {
+    output_tokens: u32,
+}
+
+#[derive(Deserialize)]
+struct StreamError {
+    message: String,
+}
+
+const DEFAULT_BASE_URL: &str = "https://api.anthropic.com/v1";
+
+/// Anthropic requires a limit on the answer's length; this one fits the
+/// output of every current Claude model.
+pub const DEFAULT_MAX_TOKENS: u32 = 8192;
+
+/// The smallest thinking budget Anthropic accepts.
+const MIN_THINKING_BUDGET: u32 = 1024;
+
+/// Upper bound for the default `max_tokens` with thinking: the output limit
+/// of Claude Opus 4 and 4.1, the lowest among the models that can think.
+const MAX_THINKING_TOKENS: u32 = 32000;
+
 pub struct AnthropicProvider {
     client: Client,
+    max_retries: u32,
+    stream_idle: Option<Duration>,
     api_key: String,
+    base_url: String,
     model: String,
 }
 
 impl AnthropicProvider {
-    pub fn new(model: String) -> Result<Self> {
-        let api_key = std::env::var("ANTHROPIC_API_KEY")
-            .map_err(|_| anyhow::anyhow!("ANTHROPIC_API_KEY environment variable not set"))?;
-
-        let model = if model == "DEFAULT" {
-            "claude-sonnet-4-20250514".to_string()
-        } else {
-            model
-        };
+    /// Sets up the provider from explicit settings; the API key must be set.
+    pub fn builder() -> ProviderBuilder<Self> {
+        ProviderBuilder::new("anthropic", Self::new)
+    }
+
+    pub fn new(config: &ProviderConfig) -> Result<Self> {
+        let api_key = config.api_key.clone().ok_or_else(|| Error::Missing {
+            provider: "anthropic".to_string(),
+            setting: Setting::ApiKey,
+        })?;
+
+        let model = config
+            .model
+            .clone()
+            .unwrap_or_else(|| "claude-sonnet-4-20250514".to_string());
+
+        let base_url = config
+            .base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+            .to_string();
 
         Ok(Self {
-            client: Client::new(),
+            client: http::client(&config.timeouts)?,
+            max_retries: config.max_retries.unwrap_or(retry::DEFAULT_MAX_RETRIES),
+            stream_idle: config.timeouts.stream_idle()?,
             api_key,
+            base_url,
             model,
         })
     }
-}
 
-#[async_trait::async_trait]
-impl AiProvider for AnthropicProvider {
-    async fn query(&self, prompt: &str) -> Result<AiResponse> {
-        let start = Instant::now();
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<(Response, u32)> {
+        if request.options.seed.is_some() {
+            warnings.push("Anthropic does not support seed; ignoring it".to_string());
+        }
+        if request.options.candidates.is_some_and(|n| n > 1) {
+            warnings.push("Anthropic does not support n; ignoring it".to_string());
+        }
+
+        let budget = request.options.thinking.map(Thinking::budget);
+        if let Some(budget) = budget.filter(|budget| *budget < MIN_THINKING_BUDGET) {
+            return Err(Error::Config(format!(
+                "Anthropic needs a thinking budget of at least {} tokens, got {}",
+                MIN_THINKING_BUDGET, budget
+            )));
+        }
+
+        // The thinking budget counts towards `max_tokens`. A limit that was
+        // given covers both; the default leaves room for an answer after the
+        // budget, within every thinking model's output limit.
+        let max_tokens = match (request.options.max_tokens, budget) {
+            (Some(max_tokens), _) => max_tokens,
+            (None, Some(budget)) => budget
+                .saturating_add(DEFAULT_MAX_TOKENS)
+                .min(MAX_THINKING_TOKENS),
+            (None, None) => DEFAULT_MAX_TOKENS,
+        };
+        if let Some(budget) = budget.filter(|budget| *budget >= max_tokens) {
+            return Err(Error::Config(format!(
+                "Anthropic's thinking budget of {} tokens leaves no room for the answer within max_tokens of {}",
+                budget, max_tokens
+            )));
+        }
+
+        // Thinking only works with the default temperature and top_k, and
+        // with top_p from 0.95.
+        let mut options = request.options.clone();
+        if budget.is_some() {
+            if options.temperature.take().is_some() {
+                warnings.push(
+                    "Anthropic does not support temperature with thinking; ignoring it".to_string(),
+                );
+            }
+            if options.top_k.take().is_some() {
+                warnings.push(
+                    "Anthropic does not support top_k with thinking; ignoring it".to_string(),
+                );
+            }
+            if options.top_p.take_if(|top_p| *top_p < 0.95).is_some() {
+                warnings.push(
+                    "Anthropic needs a top_p of at least 0.95 with thinking; ignoring it"
+                        .to_string(),
+                );
+            }
+        }
 
         let request_body = AnthropicRequest {
             model: self.model.clone(),
-            max_tokens: 1024,
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: prompt.to_string(),
-            }],
+            max_tokens,
+            system: request.system_with_schema(),
+            messages: messages(&request.messages),
+            temperature: options.temperature,
+            top_p: options.top_p,
+            top_k: options.top_k,
+            stop_sequences: request.options.stop.clone(),
+            thinking: budget.map(|budget_tokens| ThinkingConfig {
+                kind: "enabled",
+                budget_tokens,
+            }),
+            tools: request.tools.iter().map(Tool::from).collect(),
+            stream,
         };
 
-        let response = self
-            .client
-            .post("https://api.anthropic.com/v1/messages")
-            .header("x-api-key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .await?;
+        let (response, retries) = retry::send(
+            self.client
+                .post(format!("{}/messages", self.base_url))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", "2023-06-01")
+                .header("Content-Type", "application/json")
+                .json(&request_body),
+            self.max_retries,
+            warnings,
+        )
+        .await?;
 
-        let duration = start.elapsed();
+        let response = http::check_status(response, "Anthropic API").await?;
+
+        Ok((response, retries))
+    }
+}
+
+impl AnthropicProvider {
+    /// Reads a complete answer. Timing, retries and warnings are left for
+    /// the caller to fill in.
+    fn parse(&self, anthropic_response: AnthropicResponse) -> Result<AiResponse> {
+        let mut segments = Vec::new();
+        let mut tool_calls = Vec::new();
+        let mut reasoning = Vec::new();
+        let mut answered = false;
+
+        for block in anthropic_response.content {
+            match block {
+                ContentBlock::Text { text } => {
+                    answered = true;
+                    Segment::push(&mut segments, Segment::Text(text));
+                }
+                ContentBlock::Thinking {
+                    thinking,
+                    signature,
+                } => {
+                    Segment::push(&mut segments, Segment::Thinking(thinking.clone()));
+                    reasoning.push(ReasoningBlock::Thinking {
+                        thinking,
+                        signature,
+                    });
+                }
+                ContentBlock::RedactedThinking { data } => {
+                    reasoning.push(ReasoningBlock::Redacted { data });
+                }
+                ContentBlock::ToolUse { id, name, input } => {
+                    let call = providers::ToolCall {
+                        id,
+                        name,
+                        arguments: input,
+                    };
+                    tool_calls.push(call.clone());
+                    Segment::push(&mut segments, Segment::ToolUse(call));
+                }
+                ContentBlock::Other => {}
+            }
+        }
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await?;
-            anyhow::bail!(
-                "Anthropic API request failed with status {}: {}",
-                status,
-                text
-            );
+        let finish_reason = anthropic_response
+            .stop_reason
+            .as_deref()
+            .map(FinishReason::parse);
+        if !answered && tool_calls.is_empty() && finish_reason != Some(FinishReason::ContentFilter)
+        {
+            return Err(Error::Provider("No response from Anthropic".to_string()));
         }
 
-        let anthropic_response: AnthropicResponse = response.json().await?;
+        AiResponse {
+            content: Segment::text(&segments),
+            tool_calls,
+            segments,
+            alternatives: Vec::new(),
+            reasoning,
+            finish_reason,
+            usage: anthropic_response.usage.into(),
+            duration: Duration::ZERO,
+            model: self.model.clone(),
+            provider: "anthropic".to_string(),
+            retries: 0,
+            failures: Vec::new(),
+            warnings: Vec::new(),
+        }
+        .check_refusal("Anthropic")
+    }
 
-        let content = anthropic_response
-            .content
-            .first()
-            .map(|c| c.text.clone())
-            .ok_or_else(|| anyhow::anyhow!("No response from Anthropic"))?;
+    /// Reads a streamed answer, passing each piece to `on_chunk`. Timing,
+    /// retries and warnings are left for the caller to fill in.
+    async fn parse_stream(
+        &self,
+        response: Response,
+        on_chunk: &mut ChunkHandler<'_>,
+    ) -> Result<AiResponse> {
+        let mut content = String::new();
+        let mut segments = Vec::new();
+        let mut tool_calls = ToolCallStream::default();
+        let mut reasoning = Vec::new();
+        let mut thinking_block = String::new();
+        let mut finish_reason = None;
+        let mut usage = providers::Usage::default();
 
-        let total_tokens =
-            anthropic_response.usage.input_tokens + anthropic_response.usage.output_tokens;
+        read_lines(response, self.stream_idle, |line| {
+            let Some(data) = sse_data(line) else {
+                return Ok(());
+            };
+            match serde_json::from_str(data)? {
+                StreamEvent::MessageStart { message } => usage = message.usage.into(),
+                StreamEvent::ContentBlockStart {
+                    index,
+                    content_block: StartBlock::ToolUse { id, name },
+                } => tool_calls.update(index, Some(&id), Some(&name), None),
+                StreamEvent::ContentBlockStart {
+                    content_block: StartBlock::RedactedThinking { data },
+                    ..
+                } => reasoning.push(ReasoningBlock::Redacted { data }),
+                StreamEvent::ContentBlockDelta {
+                    delta: StreamDelta::TextDelta { text },
+                    ..
+                } => {
+                    on_chunk(Chunk::Text(&text));
+                    content.push_str(&text);
+                    Segment::push(&mut segments, Segment::Text(text));
+                }
+                StreamEvent::ContentBlockDelta {
+                    delta: StreamDelta::ThinkingDelta { thinking },
+                    ..
+                } => {
+                    on_chunk(Chunk::Thinking(&thinking));
+                    thinking_block.push_str(&thinking);
+                    Segment::push(&mut segments, Segment::Thinking(thinking));
+                }
+                StreamEvent::ContentBlockDelta {
+                    delta: StreamDelta::SignatureDelta { signature },
+                    ..
+                } => reasoning.push(ReasoningBlock::Thinking {
+                    thinking: std::mem::take(&mut thinking_block),
+                    signature,
+                }),
+                StreamEvent::ContentBlockDelta {
+                    index,
+                    delta: StreamDelta::InputJsonDelta { partial_json },
+                } => tool_calls.update(index, None, None, Some(&partial_json)),
+                StreamEvent::MessageDelta {
+                    delta,
+                    usage: delta_usage,
+                } => {
+                    usage.output_tokens = Some(delta_usage.output_tokens);
+                    if let Some(reason) = delta.stop_reason.as_deref() {
+                        finish_reason = Some(FinishReason::parse(reason));
+                    }
+                }
+                StreamEvent::Error { error } => {
+                    return Err(Error::Provider(format!(
+                        "Anthropic stream error: {}",
+                        error.message
+                    )));
+                }
+                _ => {}
+            }
+            Ok(())
+        })
+        .await?;
 
-        Ok(AiResponse {
+        let tool_calls = tool_calls.finish();
+        for call in &tool_calls {
+            Segment::push(&mut segments, Segment::ToolUse(call.clone()));
+        }
+
+        AiResponse {
             content,
-            tokens_used: Some(total_tokens),
-            duration,
+            tool_calls,
+            segments,
+            alternatives: Vec::new(),
+            reasoning,
+            finish_reason,
+            usage,
+            duration: Duration::ZERO,
             model: self.model.clone(),
             provider: "anthropic".to_string(),
+            retries: 0,
+            failures: Vec::new(),
+            warnings: Vec::new(),
+        }
+        .check_refusal("Anthropic")
+    }
+}
+
+#[async_trait::async_trait]
+impl AiProvider for AnthropicProvider {
+    async fn query(&self, request: &ChatRequest) -> Result<AiResponse> {
+        let start = Instant::now();
+
+        let mut warnings = Vec::new();
+        let (response, retries) = self.send(request, false, &mut warnings).await?;
+
+        let duration = start.elapsed();
+
+        let response = self.parse(http::json(response).await?)?;
+        Ok(AiResponse {
+            duration,
+            retries,
+            warnings,
+            ..response
         })
     }
+
+    async fn query_stream(
+        &self,
+        request: &ChatRequest,
+        on_chunk: &mut ChunkHandler<'_>,
+    ) -> Result<AiResponse> {
+        let start = Instant::now();
+
+        let mut warnings = Vec::new();
+        let (response, retries) = self.send(request, true, &mut warnings).await?;
+
+        let response = self.parse_stream(response, on_chunk).await?;
+        Ok(AiResponse {
+            duration: start.elapsed(),
+            retries,
+            warnings,
+            ..response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn provider() -> AnthropicProvider {
+        AnthropicProvider::builder()
+            .api_key("test")
+            .model("claude-sonnet-4-5")
+            .build()
+            .unwrap()
+    }
+
+    fn parse(body: &str) -> Result<AiResponse> {
+        provider().parse(serde_json::from_str(body).unwrap())
+    }
+
+    /// Parses an SSE body, returning the answer and the chunks passed on.
+    async fn parse_stream(events: &[serde_json::Value]) -> (AiResponse, Vec<String>) {
+        let body: String = events
+            .iter()
+            .map(|event| {
+                format!(
+                    "event: {}\ndata: {}\n\n",
+                    event["type"].as_str().unwrap(),
+                    event
+                )
+            })
+            .collect();
+
+        let mut chunks = Vec::new();
+        let mut on_chunk = |chunk: Chunk| {
+            chunks.push(match chunk {
+                Chunk::Text(text) => format!("text:{}", text),
+                Chunk::Thinking(text) => format!("thinking:{}", text),
+            })
+        };
+        let response = provider()
+            .parse_stream(hyper::Response::new(body).into(), &mut on_chunk)
+            .await
+            .unwrap();
+        (response, chunks)
+    }
+
+    #[test]
+    fn thinking_text_and_tool_use() {
+        let response = parse(
+            r#"{
+                "content": [
+                    {"type": "thinking", "thinking": "The user wants the time.", "signature": "sig1"},
+                    {"type": "redacted_thinking", "data": "opaque"},
+                    {"type": "text", "text": "Let me check."},
+                    {"type": "tool_use", "id": "toolu_1", "name": "current_time", "input": {}}
+                ],
+                "stop_reason": "tool_use",
+                "usage": {"input_tokens": 12, "output_tokens": 30, "cache_read_input_tokens": 100}
+            }"#,
+        )
+        .unwrap();
+
+        let call = providers::ToolCall {
+            id: "toolu_1".to_string(),
+            name: "current_time".to_string(),
+            arguments: json!({}),
+        };
+        assert_eq!(response.content, "Let me check.");
+        assert_eq!(
+            response.segments,
+            [
+                Segment::Thinking("The user wants the time.".to_string()),
+                Segment::Text("Let me check.".to_string()),
+                Segment::ToolUse(call.clone()),
+            ]
+        );
+        assert_eq!(response.tool_calls, [call]);
+        assert_eq!(
+            response.reasoning,
+            [
+                ReasoningBlock::Thinking {
+                    thinking: "The user wants the time.".to_string(),
+                    signature: "sig1".to_string(),
+                },
+                ReasoningBlock::Redacted {
+                    data: "opaque".to_string()
+                },
+            ]
+        );
+        assert_eq!(response.finish_reason, Some(FinishReason::ToolCalls));
+        assert_eq!(response.usage.input_tokens, Some(112));
+        assert_eq!(response.usage.cached_tokens, Some(100));
+        assert_eq!(response.usage.output_tokens, Some(30));
+    }
+
+    #[test]
+    fn refusal_is_a_content_filter_error() {
+        let error = parse(
+            r#"{"content": [], "stop_reason": "refusal", "usage": {"input_tokens": 5, "output_tokens": 0}}"#,
+        )
+        .unwrap_err();
+        assert!(matches!(error, Error::ContentFilter { .. }), "{error:?}");
+    }
+
+    #[test]
+    fn empty_answer_is_an_error() {
+        let error = parse(
+            r#"{"content": [], "stop_reason": "end_turn", "usage": {"input_tokens": 5, "output_tokens": 0}}"#,
+        )
+        .unwrap_err();
+        assert!(matches!(error, Error::Provider(_)), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn stream_with_thinking_and_tool_use() {
+        let (response, chunks) = parse_stream(&[
+            json!({"type": "message_start", "message": {"usage": {"input_tokens": 12, "output_tokens": 1}}}),
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "thinking", "thinking": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "thinking_delta", "thinking": "The user "}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "thinking_delta", "thinking": "wants the time."}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "signature_delta", "signature": "sig1"}}),
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({"type": "content_block_start", "index": 1, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "text_delta", "text": "Let me "}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "text_delta", "text": "check."}}),
+            json!({"type": "content_block_stop", "index": 1}),
+            json!({"type": "content_block_start", "index": 2, "content_block": {"type": "tool_use", "id": "toolu_1", "name": "read_file", "input": {}}}),
+            json!({"type": "content_block_delta", "index": 2, "delta": {"type": "input_json_delta", "partial_json": "{\"path\": "}}),
+            json!({"type": "content_block_delta", "index": 2, "delta": {"type": "input_json_delta", "partial_json": "\"Cargo.toml\"}"}}),
+            json!({"type": "content_block_stop", "index": 2}),
+            json!({"type": "message_delta", "delta": {"stop_reason": "tool_use"}, "usage": {"output_tokens": 40}}),
+            json!({"type": "message_stop"}),
+        ])
+        .await;
+
+        assert_eq!(
+            chunks,
+            [
+                "thinking:The user ",
+                "thinking:wants the time.",
+                "text:Let me ",
+                "text:check."
+            ]
+        );
+        assert_eq!(response.content, "Let me check.");
+        let call = providers::ToolCall {
+            id: "toolu_1".to_string(),
+            name: "read_file".to_string(),
+            arguments: json!({"path": "Cargo.toml"}),
+        };
+        assert_eq!(
+            response.segments,
+            [
+                Segment::Thinking("The user wants the time.".to_string()),
+                Segment::Text("Let me check.".to_string()),
+                Segment::ToolUse(call.clone()),
+            ]
+        );
+        assert_eq!(response.tool_calls, [call]);
+        assert_eq!(
+            response.reasoning,
+            [ReasoningBlock::Thinking {
+                thinking: "The user wants the time.".to_string(),
+                signature: "sig1".to_string(),
+            }]
+        );
+        assert_eq!(response.finish_reason, Some(FinishReason::ToolCalls));
+        assert_eq!(response.usage.input_tokens, Some(12));
+        assert_eq!(response.usage.output_tokens, Some(40));
+    }
 }
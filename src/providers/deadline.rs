@@ -0,0 +1,51 @@
+use crate::providers::http::Timeouts;
+use crate::providers::{AiProvider, AiResponse, ChatRequest, ChunkHandler};
+use crate::{Error, Result};
+use std::time::Duration;
+
+/// Enforces the total timeout over a whole query: every retry, the streamed
+/// answer and, for a fallback chain, every provider tried. Giving each HTTP
+/// request its own total timeout would restart the clock on each retry.
+pub struct Deadline {
+    provider: Box<dyn AiProvider + Send + Sync>,
+    total: Duration,
+}
+
+impl Deadline {
+    /// Wraps `provider` when `timeouts` sets a total timeout.
+    pub fn wrap(
+        provider: Box<dyn AiProvider + Send + Sync>,
+        timeouts: &Timeouts,
+    ) -> Result<Box<dyn AiProvider + Send + Sync>> {
+        Ok(match timeouts.total()? {
+            Some(total) => Box::new(Self { provider, total }),
+            None => provider,
+        })
+    }
+
+    fn expired(&self) -> Error {
+        Error::Timeout(format!(
+            "Request exceeded the total timeout of {}s",
+            self.total.as_secs_f64()
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl AiProvider for Deadline {
+    async fn query(&self, request: &ChatRequest) -> Result<AiResponse> {
+        tokio::time::timeout(self.total, self.provider.query(request))
+            .await
+            .map_err(|_| self.expired())?
+    }
+
+    async fn query_stream(
+        &self,
+        request: &ChatRequest,
+        on_chunk: &mut ChunkHandler<'_>,
+    ) -> Result<AiResponse> {
+        tokio::time::timeout(self.total, self.provider.query_stream(request, on_chunk))
+            .await
+            .map_err(|_| self.expired())?
+    }
+}
@@ -0,0 +1,142 @@
+use crate::providers::retry;
+use crate::providers::{
+    self, AiProvider, AiResponse, ChatRequest, Chunk, ChunkHandler, ProviderConfig, ProviderFailure,
+};
+use crate::{Error, Result};
+
+struct Backend {
+    config: ProviderConfig,
+    /// `None` when the provider could not be set up, e.g. for a missing API
+    /// key.
+    provider: Option<Box<dyn AiProvider + Send + Sync>>,
+}
+
+/// Tries each provider of a fallback chain in order. It moves on when a
+/// provider cannot be set up or fails with a transient error that its own
+/// retries did not fix; any other error ends the chain. A streaming answer is
+/// never abandoned once text has been printed.
+///
+/// The response lists the providers that failed before the one that
+/// answered, so `failures.len()` is that provider's index in the chain.
+pub struct FallbackProvider {
+    backends: Vec<Backend>,
+}
+
+impl FallbackProvider {
+    pub fn new(configs: &[ProviderConfig]) -> Self {
+        Self {
+            backends: configs
+                .iter()
+                .map(|config| Backend {
+                    config: config.clone(),
+                    provider: providers::new_provider(config).ok(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Asks each provider in turn, streaming when `on_chunk` is given.
+    async fn run(
+        &self,
+        request: &ChatRequest,
+        mut on_chunk: Option<&mut ChunkHandler<'_>>,
+    ) -> Result<AiResponse> {
+        let mut failures = Vec::new();
+        let mut warnings = Vec::new();
+
+        for (index, backend) in self.backends.iter().enumerate() {
+            let Some(provider) = &backend.provider else {
+                // Setting the provider up again returns its error, which
+                // keeps e.g. `Error::Missing` intact.
+                let error = providers::new_provider(&backend.config)
+                    .err()
+                    .unwrap_or_else(|| {
+                        Error::Config(format!("{} could not be set up", backend.config.label()))
+                    });
+                self.fail(index, error, true, &mut failures, &mut warnings)?;
+                continue;
+            };
+
+            let mut printed = false;
+            let result = match on_chunk.as_deref_mut() {
+                Some(on_chunk) => {
+                    let mut forward = |chunk: Chunk| {
+                        printed = true;
+                        on_chunk(chunk);
+                    };
+                    provider.query_stream(request, &mut forward).await
+                }
+                None => provider.query(request).await,
+            };
+
+            match result {
+                Ok(response) => {
+                    warnings.extend(response.warnings);
+                    return Ok(AiResponse {
+                        failures,
+                        warnings,
+                        ..response
+                    });
+                }
+                Err(e) => {
+                    let retriable = retry::is_retriable(&e) && !printed;
+                    self.fail(index, e, retriable, &mut failures, &mut warnings)?;
+                }
+            }
+        }
+
+        Err(Error::Config("No provider configured".to_string()))
+    }
+
+    /// Records a failed attempt. Returns the error when the chain should stop
+    /// here instead of trying the next provider.
+    fn fail(
+        &self,
+        index: usize,
+        error: Error,
+        can_fall_back: bool,
+        failures: &mut Vec<ProviderFailure>,
+        warnings: &mut Vec<String>,
+    ) -> Result<()> {
+        let label = self.backends[index].config.label();
+
+        let Some(next) = self.backends.get(index + 1).filter(|_| can_fall_back) else {
+            if failures.is_empty() {
+                return Err(error);
+            }
+            return Err(Error::Fallback {
+                provider: label,
+                tried: failures.iter().map(|f| f.provider.clone()).collect(),
+                source: Box::new(error),
+            });
+        };
+
+        warnings.push(format!(
+            "{} failed: {:#}; falling back to {}",
+            label,
+            error,
+            next.config.label()
+        ));
+        failures.push(ProviderFailure {
+            provider: label,
+            error: format!("{:#}", error),
+        });
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl AiProvider for FallbackProvider {
+    async fn query(&self, request: &ChatRequest) -> Result<AiResponse> {
+        self.run(request, None).await
+    }
+
+    async fn query_stream(
+        &self,
+        request: &ChatRequest,
+        on_chunk: &mut ChunkHandler<'_>,
+    ) -> Result<AiResponse> {
+        self.run(request, Some(on_chunk)).await
+    }
+}
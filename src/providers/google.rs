@@ -1,134 +1,535 @@
-use crate::providers::{AiProvider, AiResponse};
-use anyhow::Result;
-use reqwest::Client;
+use crate::providers::stream::{read_lines, sse_data};
+use crate::providers::{
+    self, AiProvider, AiResponse, ChatRequest, Chunk, ChunkHandler, FinishReason, ProviderBuilder,
+    ProviderConfig, Role, Segment, ToolDefinition,
+};
+use crate::providers::{http, retry};
+use crate::{Error, Result, Setting};
+use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize)]
 struct GeminiRequest {
+    #[serde(rename = "systemInstruction", skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<SystemInstruction>,
     contents: Vec<Content>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    #[serde(rename = "generationConfig")]
+    generation_config: GenerationConfig,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidate_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_config: Option<ThinkingConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<String>,
+    /// Takes a full JSON Schema, unlike `responseSchema` which only accepts
+    /// an OpenAPI subset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_json_schema: Option<serde_json::Value>,
+}
+
+/// Asks for the reasoning to be returned along with the answer.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThinkingConfig {
+    thinking_budget: u32,
+    include_thoughts: bool,
+}
+
+#[derive(Serialize)]
+struct SystemInstruction {
+    parts: Vec<Part>,
 }
 
 #[derive(Serialize)]
 struct Content {
+    role: String,
     parts: Vec<Part>,
 }
 
+/// Function responses refer to the call by name and are sent as user turns.
+/// Consecutive turns with the same role are merged so that all responses to
+/// a model turn arrive together.
+fn contents(request: &ChatRequest) -> Vec<Content> {
+    let mut contents: Vec<Content> = Vec::new();
+
+    for message in &request.messages {
+        let (role, parts) = match message.role {
+            Role::User => {
+                let images = message.images.iter().map(|image| Part::InlineData {
+                    mime_type: image.mime_type.clone(),
+                    data: image.data.clone(),
+                });
+                let text = Part::Text(message.content.clone());
+                ("user", images.chain([text]).collect())
+            }
+            Role::Assistant => {
+                let text =
+                    (!message.content.is_empty()).then(|| Part::Text(message.content.clone()));
+                let calls = message.tool_calls.iter().map(|call| Part::FunctionCall {
+                    name: call.name.clone(),
+                    args: call.arguments.clone(),
+                });
+                ("model", text.into_iter().chain(calls).collect())
+            }
+            Role::Tool => {
+                let name = message
+                    .tool_call_id
+                    .as_deref()
+                    .and_then(|id| request.tool_call(id))
+                    .map(|call| call.name.clone())
+                    .unwrap_or_default();
+                let response = Part::FunctionResponse {
+                    name,
+                    response: serde_json::json!({ "content": message.content }),
+                };
+                ("user", vec![response])
+            }
+        };
+
+        match contents.last_mut() {
+            Some(last) if last.role == role => last.parts.extend(parts),
+            _ => contents.push(Content {
+                role: role.to_string(),
+                parts,
+            }),
+        }
+    }
+
+    contents
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum Part {
+    Text(String),
+    #[serde(rename_all = "camelCase")]
+    InlineData {
+        mime_type: String,
+        data: String,
+    },
+    FunctionCall {
+        name: String,
+        args: serde_json::Value,
+    },
+    FunctionResponse {
+        name: String,
+        response: serde_json::Value,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Tool {
+    function_declarations: Vec<FunctionDeclaration>,
+}
+
 #[derive(Serialize)]
-struct Part {
-    text: String,
+struct FunctionDeclaration {
+    name: String,
+    description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameters: Option<serde_json::Value>,
+}
+
+impl From<&ToolDefinition> for FunctionDeclaration {
+    fn from(tool: &ToolDefinition) -> Self {
+        // Gemini rejects an object schema with empty `properties`, so tools
+        // without arguments are declared without parameters.
+        let no_arguments = tool
+            .parameters
+            .get("properties")
+            .and_then(serde_json::Value::as_object)
+            .is_some_and(|properties| properties.is_empty());
+
+        Self {
+            name: tool.name.clone(),
+            description: tool.description.clone(),
+            parameters: (!no_arguments).then(|| tool.parameters.clone()),
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct GeminiResponse {
+    #[serde(default)]
     candidates: Vec<Candidate>,
     #[serde(rename = "usageMetadata")]
     usage_metadata: Option<UsageMetadata>,
+    #[serde(rename = "promptFeedback")]
+    prompt_feedback: Option<PromptFeedback>,
 }
 
 #[derive(Deserialize)]
 struct Candidate {
-    content: ContentResponse,
+    content: Option<ContentResponse>,
+    #[serde(rename = "finishReason")]
+    finish_reason: Option<String>,
+}
+
+impl Candidate {
+    /// Joins the text parts that are, or are not, thoughts.
+    fn text(&self, thought: bool) -> Option<String> {
+        let texts: Vec<&str> = self
+            .content
+            .as_ref()
+            .map_or(&[][..], |c| &c.parts)
+            .iter()
+            .filter(|p| p.thought == thought)
+            .filter_map(|p| p.text.as_deref())
+            .collect();
+
+        (!texts.is_empty()).then(|| texts.concat())
+    }
+}
+
+/// Set instead of candidates when the prompt itself was blocked.
+#[derive(Deserialize)]
+struct PromptFeedback {
+    #[serde(rename = "blockReason")]
+    block_reason: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct ContentResponse {
+    #[serde(default)]
     parts: Vec<PartResponse>,
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct PartResponse {
-    text: String,
+    text: Option<String>,
+    /// Set on parts holding the model's reasoning rather than its answer.
+    #[serde(default)]
+    thought: bool,
+    function_call: Option<FunctionCall>,
+}
+
+#[derive(Deserialize)]
+struct FunctionCall {
+    name: String,
+    args: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct UsageMetadata {
-    #[serde(rename = "totalTokenCount")]
-    total_token_count: Option<u32>,
+    prompt_token_count: Option<u32>,
+    candidates_token_count: Option<u32>,
+    cached_content_token_count: Option<u32>,
+    thoughts_token_count: Option<u32>,
+}
+
+impl From<UsageMetadata> for providers::Usage {
+    fn from(usage: UsageMetadata) -> Self {
+        // Gemini counts thinking tokens separately from the candidates.
+        let output_tokens = match (usage.candidates_token_count, usage.thoughts_token_count) {
+            (None, None) => None,
+            (candidates, thoughts) => Some(candidates.unwrap_or(0) + thoughts.unwrap_or(0)),
+        };
+
+        Self {
+            input_tokens: usage.prompt_token_count,
+            output_tokens,
+            cached_tokens: usage.cached_content_token_count,
+            reasoning_tokens: usage.thoughts_token_count,
+        }
+    }
 }
 
+impl GeminiResponse {
+    /// The answer text of the first candidate, `None` if it has none.
+    fn text(&self) -> Option<String> {
+        self.candidates.first().and_then(|c| c.text(false))
+    }
+
+    fn thinking(&self) -> String {
+        self.candidates
+            .first()
+            .and_then(|c| c.text(true))
+            .unwrap_or_default()
+    }
+
+    fn alternatives(&self) -> Vec<String> {
+        self.candidates
+            .iter()
+            .skip(1)
+            .map(|c| c.text(false).unwrap_or_default())
+            .collect()
+    }
+
+    fn finish_reason(&self) -> Option<FinishReason> {
+        let blocked = self
+            .prompt_feedback
+            .as_ref()
+            .and_then(|feedback| feedback.block_reason.as_ref())
+            .map(|_| FinishReason::ContentFilter);
+
+        self.candidates
+            .first()
+            .and_then(|c| c.finish_reason.as_deref())
+            .map(FinishReason::parse)
+            .or(blocked)
+    }
+
+    /// Gemini does not assign call ids, so they are numbered from `first_id`.
+    fn tool_calls(&self, first_id: usize) -> Vec<providers::ToolCall> {
+        self.candidates
+            .first()
+            .and_then(|c| c.content.as_ref())
+            .map_or(&[][..], |c| &c.parts)
+            .iter()
+            .filter_map(|p| p.function_call.as_ref())
+            .enumerate()
+            .map(|(index, call)| providers::ToolCall {
+                id: format!("call_{}", first_id + index),
+                name: call.name.clone(),
+                arguments: call.args.clone().unwrap_or_else(|| serde_json::json!({})),
+            })
+            .collect()
+    }
+}
+
+const DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
 pub struct GoogleProvider {
     client: Client,
+    max_retries: u32,
+    stream_idle: Option<Duration>,
     api_key: String,
+    base_url: String,
     model: String,
 }
 
 impl GoogleProvider {
-    pub fn new(model: String) -> Result<Self> {
-        let model = if model == "DEFAULT" {
-            "gemini-2.5-flash".to_string()
-        } else {
-            model
-        };
+    /// Sets up the provider from explicit settings; the API key must be set.
+    pub fn builder() -> ProviderBuilder<Self> {
+        ProviderBuilder::new("google", Self::new)
+    }
+
+    pub fn new(config: &ProviderConfig) -> Result<Self> {
+        let model = config
+            .model
+            .clone()
+            .unwrap_or_else(|| "gemini-2.5-flash".to_string());
+
+        let api_key = config.api_key.clone().ok_or_else(|| Error::Missing {
+            provider: "google".to_string(),
+            setting: Setting::ApiKey,
+        })?;
 
-        let api_key = std::env::var("GOOGLE_AI_API_KEY")
-            .map_err(|_| anyhow::anyhow!("GOOGLE_AI_API_KEY environment variable not set"))?;
+        let base_url = config
+            .base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+            .to_string();
 
         Ok(Self {
-            client: Client::new(),
+            client: http::client(&config.timeouts)?,
+            max_retries: config.max_retries.unwrap_or(retry::DEFAULT_MAX_RETRIES),
+            stream_idle: config.timeouts.stream_idle()?,
             api_key,
+            base_url,
             model,
         })
     }
+
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<(Response, u32)> {
+        let request_body = GeminiRequest {
+            system_instruction: request.system.as_ref().map(|system| SystemInstruction {
+                parts: vec![Part::Text(system.clone())],
+            }),
+            contents: contents(request),
+            tools: if request.tools.is_empty() {
+                Vec::new()
+            } else {
+                vec![Tool {
+                    function_declarations: request
+                        .tools
+                        .iter()
+                        .map(FunctionDeclaration::from)
+                        .collect(),
+                }]
+            },
+            generation_config: GenerationConfig {
+                temperature: request.options.temperature,
+                top_p: request.options.top_p,
+                top_k: request.options.top_k,
+                max_output_tokens: request.options.max_tokens,
+                stop_sequences: request.options.stop.clone(),
+                seed: request.options.seed,
+                candidate_count: request.options.candidates,
+                thinking_config: request.options.thinking.map(|thinking| ThinkingConfig {
+                    thinking_budget: thinking.budget(),
+                    include_thoughts: true,
+                }),
+                response_mime_type: request
+                    .schema
+                    .as_ref()
+                    .map(|_| "application/json".to_string()),
+                response_json_schema: request.schema.clone(),
+            },
+        };
+
+        let url = if stream {
+            format!(
+                "{}/models/{}:streamGenerateContent?alt=sse&key={}",
+                self.base_url, self.model, self.api_key
+            )
+        } else {
+            format!(
+                "{}/models/{}:generateContent?key={}",
+                self.base_url, self.model, self.api_key
+            )
+        };
+
+        let (response, retries) = retry::send(
+            self.client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .json(&request_body),
+            self.max_retries,
+            warnings,
+        )
+        .await?;
+
+        let response = http::check_status(response, "Google AI API").await?;
+
+        Ok((response, retries))
+    }
 }
 
 #[async_trait::async_trait]
 impl AiProvider for GoogleProvider {
-    async fn query(&self, prompt: &str) -> Result<AiResponse> {
+    async fn query(&self, request: &ChatRequest) -> Result<AiResponse> {
         let start = Instant::now();
 
-        let request_body = GeminiRequest {
-            contents: vec![Content {
-                parts: vec![Part {
-                    text: prompt.to_string(),
-                }],
-            }],
-        };
-
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-            self.model, self.api_key
-        );
-
-        let response = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .await?;
+        let mut warnings = Vec::new();
+        let (response, retries) = self.send(request, false, &mut warnings).await?;
 
         let duration = start.elapsed();
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await?;
-            anyhow::bail!(
-                "Google AI API request failed with status {}: {}",
-                status,
-                text
-            );
-        }
+        let gemini_response: GeminiResponse = http::json(response).await?;
 
-        let gemini_response: GeminiResponse = response.json().await?;
+        let tool_calls = gemini_response.tool_calls(0);
+        let finish_reason = gemini_response.finish_reason();
+        let content = match gemini_response.text() {
+            Some(text) => text,
+            None if !tool_calls.is_empty() => String::new(),
+            None if finish_reason == Some(FinishReason::ContentFilter) => String::new(),
+            None => return Err(Error::Provider("No response from Google AI".to_string())),
+        };
 
-        let content = gemini_response
-            .candidates
-            .first()
-            .and_then(|c| c.content.parts.first())
-            .map(|p| p.text.clone())
-            .ok_or_else(|| anyhow::anyhow!("No response from Google AI"))?;
+        let segments = Segment::sequence(&gemini_response.thinking(), &content, &tool_calls);
+        let alternatives = gemini_response.alternatives();
 
-        let tokens_used = gemini_response
+        let usage = gemini_response
             .usage_metadata
-            .and_then(|u| u.total_token_count);
+            .map(Into::into)
+            .unwrap_or_default();
 
-        Ok(AiResponse {
+        AiResponse {
             content,
-            tokens_used,
+            tool_calls,
+            segments,
+            alternatives,
+            reasoning: Vec::new(),
+            finish_reason,
+            usage,
             duration,
             model: self.model.clone(),
             provider: "google".to_string(),
+            retries,
+            failures: Vec::new(),
+            warnings,
+        }
+        .check_refusal("Google AI")
+    }
+
+    async fn query_stream(
+        &self,
+        request: &ChatRequest,
+        on_chunk: &mut ChunkHandler<'_>,
+    ) -> Result<AiResponse> {
+        let start = Instant::now();
+
+        let mut warnings = Vec::new();
+        let (response, retries) = self.send(request, true, &mut warnings).await?;
+
+        let mut content = String::new();
+        let mut thinking = String::new();
+        let mut tool_calls = Vec::new();
+        let mut finish_reason = None;
+        let mut usage = providers::Usage::default();
+
+        read_lines(response, self.stream_idle, |line| {
+            let Some(data) = sse_data(line) else {
+                return Ok(());
+            };
+            let chunk: GeminiResponse = serde_json::from_str(data)?;
+            if let Some(text) = chunk.text() {
+                on_chunk(Chunk::Text(&text));
+                content.push_str(&text);
+            }
+            let thought = chunk.thinking();
+            if !thought.is_empty() {
+                on_chunk(Chunk::Thinking(&thought));
+                thinking.push_str(&thought);
+            }
+            tool_calls.extend(chunk.tool_calls(tool_calls.len()));
+            if let Some(reason) = chunk.finish_reason() {
+                finish_reason = Some(reason);
+            }
+            if let Some(usage_metadata) = chunk.usage_metadata {
+                usage = usage_metadata.into();
+            }
+            Ok(())
         })
+        .await?;
+
+        let segments = Segment::sequence(&thinking, &content, &tool_calls);
+
+        AiResponse {
+            content,
+            tool_calls,
+            segments,
+            alternatives: Vec::new(),
+            reasoning: Vec::new(),
+            finish_reason,
+            usage,
+            duration: start.elapsed(),
+            model: self.model.clone(),
+            provider: "google".to_string(),
+            retries,
+            failures: Vec::new(),
+            warnings,
+        }
+        .check_refusal("Google AI")
     }
 }
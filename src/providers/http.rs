@@ -0,0 +1,96 @@
+use crate::error::ApiError;
+use crate::{Error, Result};
+use reqwest::{Client, Response};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+
+const DEFAULT_CONNECT_TIMEOUT: f64 = 10.0;
+const DEFAULT_READ_TIMEOUT: f64 = 300.0;
+const DEFAULT_STREAM_IDLE_TIMEOUT: f64 = 120.0;
+
+/// Timeouts in seconds. Unset fields use the defaults and `0` disables a
+/// timeout. There is no total timeout unless one is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+    /// Establishing the connection.
+    pub connect: Option<f64>,
+    /// Waiting for any single read from the server.
+    pub read: Option<f64>,
+    /// The whole query, including retries and a streamed body, and with a
+    /// fallback chain every provider tried.
+    pub total: Option<f64>,
+    /// Waiting for the next chunk of a streaming response.
+    pub stream_idle: Option<f64>,
+}
+
+impl Timeouts {
+    /// Fills the fields unset in `self` from `other`.
+    pub fn or(self, other: Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.or(other.connect),
+            read: self.read.or(other.read),
+            total: self.total.or(other.total),
+            stream_idle: self.stream_idle.or(other.stream_idle),
+        }
+    }
+
+    pub fn total(&self) -> Result<Option<Duration>> {
+        duration("total", self.total.unwrap_or(0.0))
+    }
+
+    pub fn stream_idle(&self) -> Result<Option<Duration>> {
+        duration(
+            "stream idle",
+            self.stream_idle.unwrap_or(DEFAULT_STREAM_IDLE_TIMEOUT),
+        )
+    }
+}
+
+/// Passes successful responses through and turns any other status into an
+/// error carrying the status and response body, classified by
+/// `ApiError::into_error`.
+pub(crate) async fn check_status(response: Response, api: &str) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    Err(ApiError {
+        api: api.to_string(),
+        status: status.as_u16(),
+        body: response.text().await?,
+    }
+    .into_error())
+}
+
+/// Reads a JSON response body.
+pub(crate) async fn json<T: DeserializeOwned>(response: Response) -> Result<T> {
+    Ok(serde_json::from_str(&response.text().await?)?)
+}
+
+/// Builds the HTTP client shared by all providers.
+pub(crate) fn client(timeouts: &Timeouts) -> Result<Client> {
+    let mut builder = Client::builder();
+
+    if let Some(timeout) = duration(
+        "connect",
+        timeouts.connect.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+    )? {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = duration("read", timeouts.read.unwrap_or(DEFAULT_READ_TIMEOUT))? {
+        builder = builder.read_timeout(timeout);
+    }
+
+    Ok(builder.build()?)
+}
+
+fn duration(name: &str, seconds: f64) -> Result<Option<Duration>> {
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+
+    Duration::try_from_secs_f64(seconds)
+        .map(Some)
+        .map_err(|_| Error::Config(format!("Invalid {} timeout: {}", name, seconds)))
+}
@@ -1,41 +1,720 @@
-use anyhow::Result;
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
 pub mod anthropic;
 pub mod custom;
+mod deadline;
+pub mod fallback;
 pub mod google;
+pub mod http;
 pub mod ollama;
 pub mod openai;
 pub mod openrouter;
+mod retry;
+mod stream;
+mod tools;
 
+pub use retry::DEFAULT_MAX_RETRIES;
+pub use tools::{ToolCall, ToolDefinition};
+
+/// A provider's answer with its metadata.
 #[derive(Debug, Clone)]
 pub struct AiResponse {
+    /// All text the model returned.
     pub content: String,
-    pub tokens_used: Option<u32>,
+    /// Tools the model wants called before it gives its final answer.
+    pub tool_calls: Vec<ToolCall>,
+    /// The text, reasoning and tool calls in the order the model produced
+    /// them.
+    pub segments: Vec<Segment>,
+    /// Text of the other candidates when more than one was asked for.
+    pub alternatives: Vec<String>,
+    /// Reasoning to send back with the tool calls, see `ChatMessage::reasoning`.
+    pub reasoning: Vec<ReasoningBlock>,
+    /// Why the model stopped, when the provider says.
+    pub finish_reason: Option<FinishReason>,
+    pub usage: Usage,
+    /// Time from sending the request to the end of the answer.
     pub duration: Duration,
     pub model: String,
+    /// Display name of the provider that answered, e.g. `openai`.
+    pub provider: String,
+    /// Number of times the request was retried before it succeeded.
+    pub retries: u32,
+    /// Providers of a fallback chain that failed before `provider` answered.
+    pub failures: Vec<ProviderFailure>,
+    /// Things the user may want to know, e.g. retried requests or options
+    /// the provider does not support. The library never prints them.
+    pub warnings: Vec<String>,
+}
+
+impl AiResponse {
+    /// Fails with `Error::ContentFilter` when the provider withheld the whole
+    /// answer for safety reasons, so that every provider reports refusals
+    /// alike.
+    pub(crate) fn check_refusal(self, provider: &str) -> Result<Self> {
+        if self.content.is_empty()
+            && self.tool_calls.is_empty()
+            && self.finish_reason == Some(FinishReason::ContentFilter)
+        {
+            return Err(Error::ContentFilter {
+                message: format!("{} withheld the answer for safety reasons", provider),
+                response: None,
+            });
+        }
+        Ok(self)
+    }
+}
+
+/// A part of an answer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Text(String),
+    /// Reasoning the model returned alongside its answer.
+    Thinking(String),
+    ToolUse(ToolCall),
+}
+
+impl Segment {
+    /// Appends `segment`, merging it into the last one when both are text or
+    /// both are thinking. Empty text is dropped.
+    pub fn push(segments: &mut Vec<Segment>, segment: Segment) {
+        match (segments.last_mut(), segment) {
+            (_, Segment::Text(text) | Segment::Thinking(text)) if text.is_empty() => {}
+            (Some(Segment::Text(last)), Segment::Text(text))
+            | (Some(Segment::Thinking(last)), Segment::Thinking(text)) => last.push_str(&text),
+            (_, segment) => segments.push(segment),
+        }
+    }
+
+    /// Segments for an answer whose reasoning, text and tool calls are
+    /// reported separately, in that order.
+    pub fn sequence(thinking: &str, content: &str, tool_calls: &[ToolCall]) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        Segment::push(&mut segments, Segment::Thinking(thinking.to_string()));
+        Segment::push(&mut segments, Segment::Text(content.to_string()));
+        for call in tool_calls {
+            Segment::push(&mut segments, Segment::ToolUse(call.clone()));
+        }
+        segments
+    }
+
+    /// The text segments joined together.
+    pub fn text(segments: &[Segment]) -> String {
+        segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Reasoning as the provider returned it, e.g. Anthropic's signed thinking
+/// blocks, which must be sent back unchanged with the tool calls that
+/// followed it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReasoningBlock {
+    Thinking {
+        thinking: String,
+        signature: String,
+    },
+    /// Reasoning the provider returned encrypted.
+    Redacted {
+        data: String,
+    },
+}
+
+/// Why the model stopped generating, normalized across providers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The answer is complete, or a stop sequence was generated.
+    Stop,
+    /// The answer hit the output token limit and is cut off.
+    Length,
+    /// The model stopped to call tools.
+    ToolCalls,
+    /// The provider withheld or cut off the answer for safety reasons.
+    ContentFilter,
+    /// A reason without an equivalent above, lowercased.
+    Other(String),
+}
+
+impl FinishReason {
+    /// Maps the reasons reported by OpenAI (`finish_reason`), Anthropic
+    /// (`stop_reason`), Gemini (`finishReason`) and Ollama (`done_reason`).
+    pub fn parse(reason: &str) -> Self {
+        let reason = reason.to_lowercase();
+        match reason.as_str() {
+            "stop" | "end_turn" | "stop_sequence" => FinishReason::Stop,
+            "length" | "max_tokens" => FinishReason::Length,
+            "tool_calls" | "function_call" | "tool_use" => FinishReason::ToolCalls,
+            "content_filter" | "refusal" | "safety" | "recitation" | "blocklist"
+            | "prohibited_content" | "spii" => FinishReason::ContentFilter,
+            _ => FinishReason::Other(reason),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::ToolCalls => "tool_calls",
+            FinishReason::ContentFilter => "content_filter",
+            FinishReason::Other(reason) => reason,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderFailure {
+    /// `provider` or `provider:model`.
     pub provider: String,
+    pub error: String,
+}
+
+/// Token counts reported by the provider. Output tokens include any
+/// reasoning tokens; cached tokens are the part of the input served from the
+/// provider's prompt cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
+    pub cached_tokens: Option<u32>,
+    pub reasoning_tokens: Option<u32>,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> Option<u32> {
+        match (self.input_tokens, self.output_tokens) {
+            (None, None) => None,
+            (input, output) => Some(input.unwrap_or(0) + output.unwrap_or(0)),
+        }
+    }
+
+    /// Adds up the usage of several requests, e.g. the rounds of a tool loop.
+    pub fn add(&self, other: &Usage) -> Usage {
+        let add = |a: Option<u32>, b: Option<u32>| match (a, b) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        };
+
+        Usage {
+            input_tokens: add(self.input_tokens, other.input_tokens),
+            output_tokens: add(self.output_tokens, other.output_tokens),
+            cached_tokens: add(self.cached_tokens, other.cached_tokens),
+            reasoning_tokens: add(self.reasoning_tokens, other.reasoning_tokens),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+    /// The result of a tool call.
+    Tool,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        }
+    }
+}
+
+/// A single turn of a conversation, as sent to the providers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+    /// Tools the assistant called in this turn.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// For `Role::Tool`, the id of the call this is the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<Image>,
+    /// Reasoning that preceded the tool calls of an assistant turn.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reasoning: Vec<ReasoningBlock>,
+}
+
+/// An image sent with a message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Image {
+    /// e.g. `image/png`.
+    pub mime_type: String,
+    /// The image file, base64-encoded.
+    pub data: String,
+}
+
+impl Image {
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime_type, self.data)
+    }
+}
+
+impl ChatMessage {
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::User,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            reasoning: Vec::new(),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::Assistant,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            reasoning: Vec::new(),
+        }
+    }
+
+    pub fn tool_calls(content: impl Into<String>, tool_calls: Vec<ToolCall>) -> Self {
+        Self {
+            tool_calls,
+            ..Self::assistant(content)
+        }
+    }
+
+    pub fn tool_result(call: &ToolCall, output: impl Into<String>) -> Self {
+        Self {
+            role: Role::Tool,
+            content: output.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: Some(call.id.clone()),
+            images: Vec::new(),
+            reasoning: Vec::new(),
+        }
+    }
+
+    pub fn with_images(self, images: Vec<Image>) -> Self {
+        Self { images, ..self }
+    }
+
+    pub fn with_reasoning(self, reasoning: Vec<ReasoningBlock>) -> Self {
+        Self { reasoning, ..self }
+    }
+}
+
+/// Sampling and length settings. Unset fields are left to the provider,
+/// except that Anthropic requires a token limit and gets
+/// `anthropic::DEFAULT_MAX_TOKENS`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GenerationOptions {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<u32>,
+    /// Upper bound on the tokens in the answer.
+    pub max_tokens: Option<u32>,
+    /// Sequences that end the answer when generated.
+    pub stop: Vec<String>,
+    pub seed: Option<u64>,
+    /// Number of alternative answers to generate.
+    pub candidates: Option<u32>,
+    pub thinking: Option<Thinking>,
+}
+
+/// How much a reasoning model may think before answering. Providers take
+/// either a token budget or an effort level; each converts to the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Thinking {
+    Low,
+    Medium,
+    High,
+    /// Tokens the model may spend on reasoning.
+    Budget(u32),
+}
+
+impl Thinking {
+    pub fn budget(self) -> u32 {
+        match self {
+            Thinking::Low => 2048,
+            Thinking::Medium => 8192,
+            Thinking::High => 24576,
+            Thinking::Budget(tokens) => tokens,
+        }
+    }
+
+    /// `low`, `medium` or `high`; a budget maps to the nearest level.
+    pub fn effort(self) -> &'static str {
+        match self.budget() {
+            0..=4096 => "low",
+            4097..=16384 => "medium",
+            _ => "high",
+        }
+    }
 }
 
+impl std::str::FromStr for Thinking {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "low" => Ok(Thinking::Low),
+            "medium" => Ok(Thinking::Medium),
+            "high" => Ok(Thinking::High),
+            other => other.parse().map(Thinking::Budget).map_err(|_| {
+                format!(
+                    "expected a token budget or low, medium or high, got '{}'",
+                    value
+                )
+            }),
+        }
+    }
+}
+
+impl GenerationOptions {
+    /// Fills the fields unset in `self` from `other`.
+    pub fn or(self, other: GenerationOptions) -> GenerationOptions {
+        GenerationOptions {
+            temperature: self.temperature.or(other.temperature),
+            top_p: self.top_p.or(other.top_p),
+            top_k: self.top_k.or(other.top_k),
+            max_tokens: self.max_tokens.or(other.max_tokens),
+            stop: if self.stop.is_empty() {
+                other.stop
+            } else {
+                self.stop
+            },
+            seed: self.seed.or(other.seed),
+            candidates: self.candidates.or(other.candidates),
+            thinking: self.thinking.or(other.thinking),
+        }
+    }
+}
+
+/// Everything a provider needs to produce a response.
+#[derive(Debug, Clone, Default)]
+pub struct ChatRequest {
+    pub system: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    pub options: GenerationOptions,
+    /// Tools the model may call.
+    pub tools: Vec<ToolDefinition>,
+    /// JSON schema the answer must match.
+    pub schema: Option<serde_json::Value>,
+}
+
+impl ChatRequest {
+    /// The system prompt, extended with the schema for providers without a
+    /// native structured-output mode.
+    pub fn system_with_schema(&self) -> Option<String> {
+        let Some(schema) = &self.schema else {
+            return self.system.clone();
+        };
+
+        let instruction = format!(
+            "Respond only with JSON that matches this JSON Schema, without any other text or code fences:\n{}",
+            schema
+        );
+        Some(match &self.system {
+            Some(system) => format!("{}\n\n{}", system, instruction),
+            None => instruction,
+        })
+    }
+
+    /// Whether OpenAI's strict mode accepts the schema: the root is an object
+    /// and every object lists all its properties in `required` and sets
+    /// `"additionalProperties": false`. Other schemas are sent without strict
+    /// mode and only validated locally.
+    pub(crate) fn strict_schema(&self) -> bool {
+        self.schema.as_ref().is_some_and(|schema| {
+            schema.get("type").and_then(|kind| kind.as_str()) == Some("object") && is_strict(schema)
+        })
+    }
+
+    /// The most recent call with this id, for providers whose tool results
+    /// refer to the tool by name.
+    pub fn tool_call(&self, id: &str) -> Option<&ToolCall> {
+        self.messages
+            .iter()
+            .rev()
+            .flat_map(|message| &message.tool_calls)
+            .find(|call| call.id == id)
+    }
+}
+
+fn is_strict(schema: &serde_json::Value) -> bool {
+    use serde_json::Value;
+
+    let Some(schema) = schema.as_object() else {
+        // `true`/`false` schemas and the values of keywords like `enum`.
+        return true;
+    };
+
+    if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        if !properties
+            .keys()
+            .all(|name| required.contains(&name.as_str()))
+            || !properties.values().all(is_strict)
+        {
+            return false;
+        }
+    }
+    let is_object = schema.get("type").and_then(Value::as_str) == Some("object")
+        || schema.contains_key("properties");
+    if is_object && schema.get("additionalProperties") != Some(&Value::Bool(false)) {
+        return false;
+    }
+
+    let nested = |key: &str| match schema.get(key) {
+        Some(Value::Array(schemas)) => schemas.iter().all(is_strict),
+        Some(Value::Object(map)) if key == "$defs" || key == "definitions" => {
+            map.values().all(is_strict)
+        }
+        Some(schema) => is_strict(schema),
+        None => true,
+    };
+    ["items", "anyOf", "$defs", "definitions"]
+        .into_iter()
+        .all(nested)
+}
+
+/// Connection settings for a provider. Unset fields fall back to the
+/// provider's defaults; providers never read the environment themselves.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderConfig {
+    pub provider: String,
+    pub model: Option<String>,
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+    /// Display name, used by the custom provider.
+    pub name: Option<String>,
+    /// Whether the custom provider asks for token usage in streamed answers
+    /// with `stream_options`, which not every OpenAI-compatible server
+    /// accepts.
+    pub stream_usage: bool,
+    /// Retries for transient failures, `DEFAULT_MAX_RETRIES` if unset.
+    pub max_retries: Option<u32>,
+    pub timeouts: http::Timeouts,
+}
+
+impl ProviderConfig {
+    /// `provider:model`, or just the provider when it uses its default model.
+    pub fn label(&self) -> String {
+        match &self.model {
+            Some(model) => format!("{}:{}", self.provider, model),
+            None => self.provider.clone(),
+        }
+    }
+}
+
+/// Sets up a provider from explicit settings; nothing is read from the
+/// environment. Obtained from e.g. `OpenAiProvider::builder()`.
+pub struct ProviderBuilder<P> {
+    config: ProviderConfig,
+    build: fn(&ProviderConfig) -> Result<P>,
+}
+
+impl<P> ProviderBuilder<P> {
+    pub(crate) fn new(provider: &str, build: fn(&ProviderConfig) -> Result<P>) -> Self {
+        Self {
+            config: ProviderConfig {
+                provider: provider.to_string(),
+                ..Default::default()
+            },
+            build,
+        }
+    }
+
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.config.model = Some(model.into());
+        self
+    }
+
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.config.api_key = Some(api_key.into());
+        self
+    }
+
+    /// The API's base URL, or for the custom provider the full URL of its
+    /// chat completions endpoint.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.config.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Display name, used by the custom provider.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = Some(name.into());
+        self
+    }
+
+    /// Asks the custom provider's server for token usage in streamed
+    /// answers.
+    pub fn stream_usage(mut self, stream_usage: bool) -> Self {
+        self.config.stream_usage = stream_usage;
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.config.max_retries = Some(max_retries);
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: http::Timeouts) -> Self {
+        self.config.timeouts = timeouts;
+        self
+    }
+
+    /// Fails when a required setting, e.g. the API key, is missing.
+    pub fn build(self) -> Result<P> {
+        (self.build)(&self.config)
+    }
+}
+
+/// A piece of a streaming response.
+#[derive(Debug, Clone, Copy)]
+pub enum Chunk<'a> {
+    Text(&'a str),
+    /// Reasoning, streamed before the answer.
+    Thinking(&'a str),
+}
+
+/// Callback receiving each piece of a streaming response.
+pub type ChunkHandler<'a> = dyn FnMut(Chunk) + Send + 'a;
+
+/// A chat API that answers `ChatRequest`s.
 #[async_trait::async_trait]
 pub trait AiProvider {
-    async fn query(&self, prompt: &str) -> Result<AiResponse>;
+    /// Sends the request and waits for the full answer.
+    async fn query(&self, request: &ChatRequest) -> Result<AiResponse>;
+
+    /// Like `query`, but invokes `on_chunk` with each piece of text and
+    /// reasoning as it arrives. The returned response carries the full content and usage.
+    async fn query_stream(
+        &self,
+        request: &ChatRequest,
+        on_chunk: &mut ChunkHandler<'_>,
+    ) -> Result<AiResponse>;
+}
+
+/// Names accepted by `get_provider`.
+pub const PROVIDERS: &[&str] = &[
+    "openai",
+    "google",
+    "anthropic",
+    "ollama",
+    "openrouter",
+    "custom",
+];
+
+/// The provider named by `config.provider`, one of `PROVIDERS`.
+pub fn get_provider(config: &ProviderConfig) -> Result<Box<dyn AiProvider + Send + Sync>> {
+    deadline::Deadline::wrap(new_provider(config)?, &config.timeouts)
+}
+
+/// Like `get_provider`, without the total timeout, which a fallback chain
+/// applies to all its providers together.
+fn new_provider(config: &ProviderConfig) -> Result<Box<dyn AiProvider + Send + Sync>> {
+    match config.provider.to_lowercase().as_str() {
+        "openai" => Ok(Box::new(openai::OpenAiProvider::new(config)?)),
+        "google" => Ok(Box::new(google::GoogleProvider::new(config)?)),
+        "anthropic" => Ok(Box::new(anthropic::AnthropicProvider::new(config)?)),
+        "ollama" => Ok(Box::new(ollama::OllamaProvider::new(config)?)),
+        "openrouter" => Ok(Box::new(openrouter::OpenRouterProvider::new(config)?)),
+        "custom" => Ok(Box::new(custom::CustomProvider::new(config)?)),
+        _ => Err(Error::Config(format!(
+            "Unsupported provider: {}",
+            config.provider
+        ))),
+    }
+}
+
+/// Like `get_provider`, but falls back along `configs` in order when there is
+/// more than one. The total timeout of the first config covers the chain.
+pub fn get_provider_chain(configs: &[ProviderConfig]) -> Result<Box<dyn AiProvider + Send + Sync>> {
+    match configs {
+        [] => Err(Error::Config("No provider configured".to_string())),
+        [config] => get_provider(config),
+        _ => deadline::Deadline::wrap(
+            Box::new(fallback::FallbackProvider::new(configs)),
+            &configs[0].timeouts,
+        ),
+    }
 }
 
-pub fn get_provider() -> Result<Box<dyn AiProvider + Send + Sync>> {
-    let provider = std::env::var("CLM_PROVIDER")
-        .unwrap_or_else(|_| "google".to_string())
-        .to_lowercase();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn strict(schema: serde_json::Value) -> bool {
+        ChatRequest {
+            schema: Some(schema),
+            ..Default::default()
+        }
+        .strict_schema()
+    }
 
-    let model = std::env::var("CLM_MODEL").unwrap_or_else(|_| "DEFAULT".to_string());
+    #[test]
+    fn strict_schema_accepted() {
+        assert!(strict(json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "tags": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": { "label": { "type": "string" } },
+                        "required": ["label"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["name", "tags"],
+            "additionalProperties": false
+        })));
+    }
 
-    match provider.as_str() {
-        "openai" => Ok(Box::new(openai::OpenAiProvider::new(model)?)),
-        "google" => Ok(Box::new(google::GoogleProvider::new(model)?)),
-        "anthropic" => Ok(Box::new(anthropic::AnthropicProvider::new(model)?)),
-        "ollama" => Ok(Box::new(ollama::OllamaProvider::new(model)?)),
-        "openrouter" => Ok(Box::new(openrouter::OpenRouterProvider::new(model)?)),
-        "custom" => Ok(Box::new(custom::CustomProvider::new(model)?)),
-        _ => anyhow::bail!("Unsupported provider: {}", provider),
+    #[test]
+    fn ordinary_schemas_not_strict() {
+        // Missing additionalProperties.
+        assert!(!strict(json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" } },
+            "required": ["name"]
+        })));
+        // Optional property.
+        assert!(!strict(json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" }, "born": { "type": "integer" } },
+            "required": ["name"],
+            "additionalProperties": false
+        })));
+        // Nested object that is not strict.
+        assert!(!strict(json!({
+            "type": "object",
+            "properties": { "items": { "type": "array", "items": { "type": "object" } } },
+            "required": ["items"],
+            "additionalProperties": false
+        })));
+        // Root must be an object.
+        assert!(!strict(
+            json!({ "type": "array", "items": { "type": "string" } })
+        ));
     }
 }
@@ -1,96 +1,397 @@
-use crate::providers::{AiProvider, AiResponse};
-use anyhow::Result;
-use reqwest::Client;
+use crate::providers::stream::read_lines;
+use crate::providers::{
+    self, AiProvider, AiResponse, ChatMessage, ChatRequest, Chunk, ChunkHandler, FinishReason,
+    ProviderBuilder, ProviderConfig, Segment, Thinking, ToolDefinition,
+};
+use crate::providers::{http, retry};
+use crate::{Error, Result};
+use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize)]
 struct OllamaRequest {
     model: String,
-    prompt: String,
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
     stream: bool,
+    /// JSON schema the answer must match.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+    /// Ollama has no budget; thinking is only switched on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    think: Option<bool>,
+    options: Options,
+}
+
+#[derive(Serialize)]
+struct Options {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    /// Ollama's name for the output token limit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Message {
+    role: String,
+    content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    /// Tool results refer to the call by name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_name: Option<String>,
+    /// Base64-encoded images.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+impl Message {
+    fn system(content: &str) -> Self {
+        Self {
+            role: "system".to_string(),
+            content: content.to_string(),
+            tool_calls: Vec::new(),
+            tool_name: None,
+            images: Vec::new(),
+        }
+    }
+
+    fn new(message: &ChatMessage, request: &ChatRequest) -> Self {
+        Self {
+            role: message.role.as_str().to_string(),
+            content: message.content.clone(),
+            tool_calls: message
+                .tool_calls
+                .iter()
+                .map(|call| ToolCall {
+                    function: FunctionCall {
+                        name: call.name.clone(),
+                        arguments: call.arguments.clone(),
+                    },
+                })
+                .collect(),
+            tool_name: message
+                .tool_call_id
+                .as_deref()
+                .and_then(|id| request.tool_call(id))
+                .map(|call| call.name.clone()),
+            images: message
+                .images
+                .iter()
+                .map(|image| image.data.clone())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Tool {
+    #[serde(rename = "type")]
+    kind: String,
+    function: Function,
+}
+
+#[derive(Serialize)]
+struct Function {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+}
+
+impl From<&ToolDefinition> for Tool {
+    fn from(tool: &ToolDefinition) -> Self {
+        Self {
+            kind: "function".to_string(),
+            function: Function {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                parameters: tool.parameters.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ToolCall {
+    function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FunctionCall {
+    name: String,
+    arguments: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct MessageResponse {
+    #[serde(default)]
+    content: String,
+    /// Reasoning of thinking models.
+    #[serde(default)]
+    thinking: String,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
 }
 
 #[derive(Deserialize)]
 struct OllamaResponse {
-    response: String,
+    message: Option<MessageResponse>,
+    #[serde(default)]
     done: bool,
+    done_reason: Option<String>,
     eval_count: Option<u32>,
     prompt_eval_count: Option<u32>,
+    error: Option<String>,
+}
+
+impl OllamaResponse {
+    fn text(&self) -> &str {
+        self.message.as_ref().map_or("", |m| m.content.as_str())
+    }
+
+    fn thinking(&self) -> &str {
+        self.message.as_ref().map_or("", |m| m.thinking.as_str())
+    }
+
+    /// Ollama does not assign call ids, so they are numbered from `first_id`.
+    fn tool_calls(&self, first_id: usize) -> Vec<providers::ToolCall> {
+        self.message
+            .as_ref()
+            .map_or(&[][..], |m| &m.tool_calls)
+            .iter()
+            .enumerate()
+            .map(|(index, call)| providers::ToolCall {
+                id: format!("call_{}", first_id + index),
+                name: call.function.name.clone(),
+                arguments: call.function.arguments.clone(),
+            })
+            .collect()
+    }
+
+    fn usage(&self) -> providers::Usage {
+        providers::Usage {
+            input_tokens: self.prompt_eval_count,
+            output_tokens: self.eval_count,
+            ..Default::default()
+        }
+    }
 }
 
 pub struct OllamaProvider {
     client: Client,
+    max_retries: u32,
+    stream_idle: Option<Duration>,
     base_url: String,
     model: String,
 }
 
 impl OllamaProvider {
-    pub fn new(model: String) -> Result<Self> {
-        let base_url = std::env::var("OLLAMA_BASE_URL")
-            .unwrap_or_else(|_| "http://localhost:11434".to_string());
-
-        let model = if model == "DEFAULT" {
-            "llama3.2".to_string()
-        } else {
-            model
-        };
+    /// Sets up the provider from explicit settings, all of them optional.
+    pub fn builder() -> ProviderBuilder<Self> {
+        ProviderBuilder::new("ollama", Self::new)
+    }
+
+    pub fn new(config: &ProviderConfig) -> Result<Self> {
+        let base_url = config
+            .base_url
+            .as_deref()
+            .unwrap_or("http://localhost:11434")
+            .trim_end_matches('/')
+            .to_string();
+
+        let model = config
+            .model
+            .clone()
+            .unwrap_or_else(|| "llama3.2".to_string());
 
         Ok(Self {
-            client: Client::new(),
+            client: http::client(&config.timeouts)?,
+            max_retries: config.max_retries.unwrap_or(retry::DEFAULT_MAX_RETRIES),
+            stream_idle: config.timeouts.stream_idle()?,
             base_url,
             model,
         })
     }
-}
 
-#[async_trait::async_trait]
-impl AiProvider for OllamaProvider {
-    async fn query(&self, prompt: &str) -> Result<AiResponse> {
-        let start = Instant::now();
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<(Response, u32)> {
+        if request.options.candidates.is_some_and(|n| n > 1) {
+            warnings.push("Ollama does not support n; ignoring it".to_string());
+        }
+        if let Some(Thinking::Budget(_)) = request.options.thinking {
+            warnings.push(
+                "Ollama does not support a thinking budget; thinking without a limit".to_string(),
+            );
+        }
 
         let request_body = OllamaRequest {
             model: self.model.clone(),
-            prompt: prompt.to_string(),
-            stream: false,
+            messages: request
+                .system
+                .iter()
+                .map(|system| Message::system(system))
+                .chain(
+                    request
+                        .messages
+                        .iter()
+                        .map(|message| Message::new(message, request)),
+                )
+                .collect(),
+            tools: request.tools.iter().map(Tool::from).collect(),
+            stream,
+            format: request.schema.clone(),
+            think: request.options.thinking.map(|_| true),
+            options: Options {
+                temperature: request.options.temperature,
+                top_p: request.options.top_p,
+                top_k: request.options.top_k,
+                num_predict: request.options.max_tokens,
+                stop: request.options.stop.clone(),
+                seed: request.options.seed,
+            },
         };
 
-        let url = format!("{}/api/generate", self.base_url);
+        let url = format!("{}/api/chat", self.base_url);
 
-        let response = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .await?;
+        let (response, retries) = retry::send(
+            self.client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .json(&request_body),
+            self.max_retries,
+            warnings,
+        )
+        .await?;
 
-        let duration = start.elapsed();
+        let response = http::check_status(response, "Ollama API").await?;
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await?;
-            anyhow::bail!("Ollama API request failed with status {}: {}", status, text);
-        }
+        Ok((response, retries))
+    }
+}
 
-        let ollama_response: OllamaResponse = response.json().await?;
+#[async_trait::async_trait]
+impl AiProvider for OllamaProvider {
+    async fn query(&self, request: &ChatRequest) -> Result<AiResponse> {
+        let start = Instant::now();
+
+        let mut warnings = Vec::new();
+        let (response, retries) = self.send(request, false, &mut warnings).await?;
+
+        let duration = start.elapsed();
+
+        let ollama_response: OllamaResponse = http::json(response).await?;
 
         if !ollama_response.done {
-            anyhow::bail!("Incomplete response from Ollama");
+            return Err(Error::Provider(
+                "Incomplete response from Ollama".to_string(),
+            ));
         }
 
-        let tokens_used = ollama_response.eval_count.and_then(|eval| {
-            ollama_response
-                .prompt_eval_count
-                .map(|prompt| eval + prompt)
-        });
+        let content = ollama_response.text().to_string();
+        let tool_calls = ollama_response.tool_calls(0);
+        let segments = Segment::sequence(ollama_response.thinking(), &content, &tool_calls);
 
-        Ok(AiResponse {
-            content: ollama_response.response,
-            tokens_used,
+        AiResponse {
+            content,
+            tool_calls,
+            segments,
+            alternatives: Vec::new(),
+            reasoning: Vec::new(),
+            finish_reason: ollama_response
+                .done_reason
+                .as_deref()
+                .map(FinishReason::parse),
+            usage: ollama_response.usage(),
             duration,
             model: self.model.clone(),
             provider: "ollama".to_string(),
+            retries,
+            failures: Vec::new(),
+            warnings,
+        }
+        .check_refusal("Ollama")
+    }
+
+    async fn query_stream(
+        &self,
+        request: &ChatRequest,
+        on_chunk: &mut ChunkHandler<'_>,
+    ) -> Result<AiResponse> {
+        let start = Instant::now();
+
+        let mut warnings = Vec::new();
+        let (response, retries) = self.send(request, true, &mut warnings).await?;
+
+        let mut content = String::new();
+        let mut thinking = String::new();
+        let mut tool_calls = Vec::new();
+        let mut finish_reason = None;
+        let mut usage = providers::Usage::default();
+        let mut done = false;
+
+        read_lines(response, self.stream_idle, |line| {
+            let chunk: OllamaResponse = serde_json::from_str(line)?;
+            if let Some(error) = chunk.error {
+                return Err(Error::Provider(format!("Ollama stream error: {}", error)));
+            }
+            let text = chunk.text();
+            if !text.is_empty() {
+                on_chunk(Chunk::Text(text));
+                content.push_str(text);
+            }
+            let thought = chunk.thinking();
+            if !thought.is_empty() {
+                on_chunk(Chunk::Thinking(thought));
+                thinking.push_str(thought);
+            }
+            tool_calls.extend(chunk.tool_calls(tool_calls.len()));
+            if chunk.done {
+                done = true;
+                finish_reason = chunk.done_reason.as_deref().map(FinishReason::parse);
+                usage = chunk.usage();
+            }
+            Ok(())
         })
+        .await?;
+
+        if !done {
+            return Err(Error::Provider(
+                "Incomplete response from Ollama".to_string(),
+            ));
+        }
+
+        let segments = Segment::sequence(&thinking, &content, &tool_calls);
+
+        AiResponse {
+            content,
+            tool_calls,
+            segments,
+            alternatives: Vec::new(),
+            reasoning: Vec::new(),
+            finish_reason,
+            usage,
+            duration: start.elapsed(),
+            model: self.model.clone(),
+            provider: "ollama".to_string(),
+            retries,
+            failures: Vec::new(),
+            warnings,
+        }
+        .check_refusal("Ollama")
     }
 }
@@ -1,24 +1,202 @@
-use crate::providers::{AiProvider, AiResponse};
-use anyhow::Result;
-use reqwest::Client;
+use crate::providers::stream::{read_lines, sse_data};
+use crate::providers::tools::ToolCallStream;
+use crate::providers::{
+    self, AiProvider, AiResponse, ChatMessage, ChatRequest, Chunk, ChunkHandler, FinishReason,
+    ProviderBuilder, ProviderConfig, Segment, Thinking, ToolDefinition,
+};
+use crate::providers::{http, retry};
+use crate::{Error, Result, Setting};
+use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize)]
 struct OpenAiRequest {
     model: String,
     messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    /// Number of choices to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
 }
 
 #[derive(Serialize)]
 struct Message {
     role: String,
-    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<Content>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+impl Message {
+    fn system(content: &str) -> Self {
+        Self {
+            role: "system".to_string(),
+            content: Some(Content::Text(content.to_string())),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
+impl From<&ChatMessage> for Message {
+    fn from(message: &ChatMessage) -> Self {
+        Self {
+            role: message.role.as_str().to_string(),
+            content: Content::new(message),
+            tool_calls: message.tool_calls.iter().map(ToolCall::from).collect(),
+            tool_call_id: message.tool_call_id.clone(),
+        }
+    }
+}
+
+/// Plain text, or a list of parts when the message has images.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize)]
+struct ImageUrl {
+    url: String,
+}
+
+impl Content {
+    fn new(message: &ChatMessage) -> Option<Self> {
+        if message.images.is_empty() {
+            // An assistant turn that only calls tools has no content.
+            return (message.tool_calls.is_empty() || !message.content.is_empty())
+                .then(|| Content::Text(message.content.clone()));
+        }
+
+        let text = (!message.content.is_empty()).then(|| ContentPart::Text {
+            text: message.content.clone(),
+        });
+        let images = message.images.iter().map(|image| ContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: image.data_url(),
+            },
+        });
+        Some(Content::Parts(text.into_iter().chain(images).collect()))
+    }
+}
+
+#[derive(Serialize)]
+struct Tool {
+    #[serde(rename = "type")]
+    kind: String,
+    function: Function,
+}
+
+#[derive(Serialize)]
+struct Function {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+}
+
+impl From<&ToolDefinition> for Tool {
+    fn from(tool: &ToolDefinition) -> Self {
+        Self {
+            kind: "function".to_string(),
+            function: Function {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                parameters: tool.parameters.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    function: FunctionCall,
+}
+
+/// `arguments` is a JSON object encoded as a string.
+#[derive(Serialize, Deserialize)]
+struct FunctionCall {
+    name: String,
+    arguments: String,
+}
+
+impl From<&providers::ToolCall> for ToolCall {
+    fn from(call: &providers::ToolCall) -> Self {
+        Self {
+            id: call.id.clone(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: call.name.clone(),
+                arguments: call.arguments.to_string(),
+            },
+        }
+    }
+}
+
+impl From<ToolCall> for providers::ToolCall {
+    fn from(call: ToolCall) -> Self {
+        Self::new(call.id, call.function.name, &call.function.arguments)
+    }
+}
+
+#[derive(Serialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    kind: String,
+    json_schema: JsonSchema,
+}
+
+#[derive(Serialize)]
+struct JsonSchema {
+    name: String,
+    schema: serde_json::Value,
+    /// Makes the API enforce the schema rather than treat it as a hint.
+    strict: bool,
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
 }
 
 #[derive(Deserialize)]
 struct MessageResponse {
-    content: String,
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
 }
 
 #[derive(Deserialize)]
@@ -30,84 +208,457 @@ struct OpenAiResponse {
 #[derive(Deserialize)]
 struct Choice {
     message: MessageResponse,
+    finish_reason: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct Usage {
-    total_tokens: u32,
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    prompt_tokens_details: Option<PromptTokensDetails>,
+    completion_tokens_details: Option<CompletionTokensDetails>,
 }
 
+#[derive(Deserialize)]
+struct PromptTokensDetails {
+    cached_tokens: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct CompletionTokensDetails {
+    reasoning_tokens: Option<u32>,
+}
+
+impl From<Usage> for providers::Usage {
+    fn from(usage: Usage) -> Self {
+        Self {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+            cached_tokens: usage.prompt_tokens_details.and_then(|d| d.cached_tokens),
+            reasoning_tokens: usage
+                .completion_tokens_details
+                .and_then(|d| d.reasoning_tokens),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StreamChunk {
+    choices: Vec<StreamChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: Delta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallDelta>,
+}
+
+#[derive(Deserialize)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionDelta>,
+}
+
+#[derive(Deserialize)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
 pub struct OpenAiProvider {
     client: Client,
+    max_retries: u32,
+    stream_idle: Option<Duration>,
     api_key: String,
+    base_url: String,
     model: String,
 }
 
 impl OpenAiProvider {
-    pub fn new(model: String) -> Result<Self> {
-        let api_key = std::env::var("OPENAI_API_KEY")
-            .map_err(|_| anyhow::anyhow!("OPENAI_API_KEY environment variable not set"))?;
-
-        let model = if model == "DEFAULT" {
-            "gpt-4.1-mini".to_string()
-        } else {
-            model
-        };
+    /// Sets up the provider from explicit settings; the API key must be set.
+    pub fn builder() -> ProviderBuilder<Self> {
+        ProviderBuilder::new("openai", Self::new)
+    }
+
+    pub fn new(config: &ProviderConfig) -> Result<Self> {
+        let api_key = config.api_key.clone().ok_or_else(|| Error::Missing {
+            provider: "openai".to_string(),
+            setting: Setting::ApiKey,
+        })?;
+
+        let model = config
+            .model
+            .clone()
+            .unwrap_or_else(|| "gpt-4.1-mini".to_string());
+
+        let base_url = config
+            .base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+            .to_string();
 
         Ok(Self {
-            client: Client::new(),
+            client: http::client(&config.timeouts)?,
+            max_retries: config.max_retries.unwrap_or(retry::DEFAULT_MAX_RETRIES),
+            stream_idle: config.timeouts.stream_idle()?,
             api_key,
+            base_url,
             model,
         })
     }
-}
 
-#[async_trait::async_trait]
-impl AiProvider for OpenAiProvider {
-    async fn query(&self, prompt: &str) -> Result<AiResponse> {
-        let start = Instant::now();
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<(Response, u32)> {
+        if request.options.top_k.is_some() {
+            warnings.push("OpenAI does not support top_k; ignoring it".to_string());
+        }
 
         let request_body = OpenAiRequest {
             model: self.model.clone(),
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: prompt.to_string(),
-            }],
+            messages: request
+                .system
+                .iter()
+                .map(|system| Message::system(system))
+                .chain(request.messages.iter().map(Message::from))
+                .collect(),
+            temperature: request.options.temperature,
+            top_p: request.options.top_p,
+            max_completion_tokens: request.options.max_tokens,
+            stop: request.options.stop.clone(),
+            seed: request.options.seed,
+            n: request.options.candidates,
+            reasoning_effort: request.options.thinking.map(Thinking::effort),
+            tools: request.tools.iter().map(Tool::from).collect(),
+            response_format: request.schema.as_ref().map(|schema| ResponseFormat {
+                kind: "json_schema".to_string(),
+                json_schema: JsonSchema {
+                    name: "response".to_string(),
+                    schema: schema.clone(),
+                    strict: request.strict_schema(),
+                },
+            }),
+            stream,
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
         };
 
-        let response = self
-            .client
-            .post("https://api.openai.com/v1/chat/completions")
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .await?;
+        let (response, retries) = retry::send(
+            self.client
+                .post(format!("{}/chat/completions", self.base_url))
+                .header("Authorization", format!("Bearer {}", self.api_key))
+                .header("Content-Type", "application/json")
+                .json(&request_body),
+            self.max_retries,
+            warnings,
+        )
+        .await?;
 
-        let duration = start.elapsed();
+        let response = http::check_status(response, "OpenAI API").await?;
+
+        Ok((response, retries))
+    }
+}
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await?;
-            anyhow::bail!("OpenAI API request failed with status {}: {}", status, text);
+impl OpenAiProvider {
+    /// Reads a complete answer. Timing, retries and warnings are left for
+    /// the caller to fill in.
+    fn parse(&self, openai_response: OpenAiResponse) -> Result<AiResponse> {
+        let mut choices = openai_response.choices.into_iter();
+        let choice = choices
+            .next()
+            .ok_or_else(|| Error::Provider("No response from OpenAI".to_string()))?;
+        let alternatives = choices
+            .map(|choice| choice.message.content.unwrap_or_default())
+            .collect();
+        let message = choice.message;
+
+        let content = message.content.unwrap_or_default();
+        let tool_calls: Vec<providers::ToolCall> =
+            message.tool_calls.into_iter().map(Into::into).collect();
+        let segments = Segment::sequence("", &content, &tool_calls);
+
+        AiResponse {
+            content,
+            tool_calls,
+            segments,
+            alternatives,
+            reasoning: Vec::new(),
+            finish_reason: choice.finish_reason.as_deref().map(FinishReason::parse),
+            usage: openai_response.usage.into(),
+            duration: Duration::ZERO,
+            model: self.model.clone(),
+            provider: "openai".to_string(),
+            retries: 0,
+            failures: Vec::new(),
+            warnings: Vec::new(),
         }
+        .check_refusal("OpenAI")
+    }
 
-        let openai_response: OpenAiResponse = response.json().await?;
+    /// Reads a streamed answer, passing each piece to `on_chunk`. Timing,
+    /// retries and warnings are left for the caller to fill in.
+    async fn parse_stream(
+        &self,
+        response: Response,
+        on_chunk: &mut ChunkHandler<'_>,
+    ) -> Result<AiResponse> {
+        let mut content = String::new();
+        let mut tool_calls = ToolCallStream::default();
+        let mut finish_reason = None;
+        let mut usage = providers::Usage::default();
 
-        let content = openai_response
-            .choices
-            .first()
-            .ok_or_else(|| anyhow::anyhow!("No response from OpenAI"))?
-            .message
-            .content
-            .clone();
+        read_lines(response, self.stream_idle, |line| {
+            let Some(data) = sse_data(line) else {
+                return Ok(());
+            };
+            let chunk: StreamChunk = serde_json::from_str(data)?;
+            if let Some(choice) = chunk.choices.first() {
+                if let Some(text) = choice.delta.content.as_deref() {
+                    on_chunk(Chunk::Text(text));
+                    content.push_str(text);
+                }
+                for call in &choice.delta.tool_calls {
+                    let function = call.function.as_ref();
+                    tool_calls.update(
+                        call.index,
+                        call.id.as_deref(),
+                        function.and_then(|f| f.name.as_deref()),
+                        function.and_then(|f| f.arguments.as_deref()),
+                    );
+                }
+                if let Some(reason) = choice.finish_reason.as_deref() {
+                    finish_reason = Some(FinishReason::parse(reason));
+                }
+            }
+            if let Some(chunk_usage) = chunk.usage {
+                usage = chunk_usage.into();
+            }
+            Ok(())
+        })
+        .await?;
 
-        Ok(AiResponse {
+        let tool_calls = tool_calls.finish();
+        let segments = Segment::sequence("", &content, &tool_calls);
+
+        AiResponse {
             content,
-            tokens_used: Some(openai_response.usage.total_tokens),
-            duration,
+            tool_calls,
+            segments,
+            alternatives: Vec::new(),
+            reasoning: Vec::new(),
+            finish_reason,
+            usage,
+            duration: Duration::ZERO,
             model: self.model.clone(),
             provider: "openai".to_string(),
+            retries: 0,
+            failures: Vec::new(),
+            warnings: Vec::new(),
+        }
+        .check_refusal("OpenAI")
+    }
+}
+
+#[async_trait::async_trait]
+impl AiProvider for OpenAiProvider {
+    async fn query(&self, request: &ChatRequest) -> Result<AiResponse> {
+        let start = Instant::now();
+
+        let mut warnings = Vec::new();
+        let (response, retries) = self.send(request, false, &mut warnings).await?;
+
+        let duration = start.elapsed();
+
+        let response = self.parse(http::json(response).await?)?;
+        Ok(AiResponse {
+            duration,
+            retries,
+            warnings,
+            ..response
         })
     }
+
+    async fn query_stream(
+        &self,
+        request: &ChatRequest,
+        on_chunk: &mut ChunkHandler<'_>,
+    ) -> Result<AiResponse> {
+        let start = Instant::now();
+
+        let mut warnings = Vec::new();
+        let (response, retries) = self.send(request, true, &mut warnings).await?;
+
+        let response = self.parse_stream(response, on_chunk).await?;
+        Ok(AiResponse {
+            duration: start.elapsed(),
+            retries,
+            warnings,
+            ..response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn provider() -> OpenAiProvider {
+        OpenAiProvider::builder()
+            .api_key("test")
+            .model("gpt-4.1-mini")
+            .build()
+            .unwrap()
+    }
+
+    fn parse(body: serde_json::Value) -> Result<AiResponse> {
+        provider().parse(serde_json::from_value(body).unwrap())
+    }
+
+    /// Parses an SSE body, returning the answer and the text passed on.
+    async fn parse_stream(chunks: &[serde_json::Value]) -> (AiResponse, Vec<String>) {
+        let body: String = chunks
+            .iter()
+            .map(|chunk| format!("data: {}\n\n", chunk))
+            .chain(["data: [DONE]\n\n".to_string()])
+            .collect();
+
+        let mut texts = Vec::new();
+        let mut on_chunk = |chunk: Chunk| {
+            if let Chunk::Text(text) = chunk {
+                texts.push(text.to_string());
+            }
+        };
+        let response = provider()
+            .parse_stream(hyper::Response::new(body).into(), &mut on_chunk)
+            .await
+            .unwrap();
+        (response, texts)
+    }
+
+    #[test]
+    fn several_choices_become_alternatives() {
+        let response = parse(json!({
+            "choices": [
+                {"index": 0, "message": {"role": "assistant", "content": "Blue."}, "finish_reason": "stop"},
+                {"index": 1, "message": {"role": "assistant", "content": "Teal."}, "finish_reason": "stop"},
+                {"index": 2, "message": {"role": "assistant", "content": null}, "finish_reason": "content_filter"}
+            ],
+            "usage": {
+                "prompt_tokens": 9,
+                "completion_tokens": 6,
+                "prompt_tokens_details": {"cached_tokens": 0},
+                "completion_tokens_details": {"reasoning_tokens": 0}
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(response.content, "Blue.");
+        assert_eq!(response.segments, [Segment::Text("Blue.".to_string())]);
+        assert_eq!(response.alternatives, ["Teal.", ""]);
+        assert_eq!(response.finish_reason, Some(FinishReason::Stop));
+        assert_eq!(response.usage.total_tokens(), Some(15));
+    }
+
+    #[test]
+    fn tool_calls_without_text() {
+        let response = parse(json!({
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [
+                        {"id": "call_1", "type": "function", "function": {"name": "read_file", "arguments": "{\"path\":\"Cargo.toml\"}"}},
+                        {"id": "call_2", "type": "function", "function": {"name": "current_time", "arguments": "{}"}}
+                    ]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 20, "completion_tokens": 15}
+        }))
+        .unwrap();
+
+        assert_eq!(response.content, "");
+        assert_eq!(response.tool_calls.len(), 2);
+        assert_eq!(
+            response.tool_calls[0].arguments,
+            json!({"path": "Cargo.toml"})
+        );
+        assert_eq!(
+            response.segments,
+            [
+                Segment::ToolUse(response.tool_calls[0].clone()),
+                Segment::ToolUse(response.tool_calls[1].clone()),
+            ]
+        );
+        assert_eq!(response.finish_reason, Some(FinishReason::ToolCalls));
+    }
+
+    #[test]
+    fn withheld_answer_is_a_content_filter_error() {
+        let error = parse(json!({
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": null}, "finish_reason": "content_filter"}],
+            "usage": {"prompt_tokens": 9, "completion_tokens": 0}
+        }))
+        .unwrap_err();
+        assert!(matches!(error, Error::ContentFilter { .. }), "{error:?}");
+    }
+
+    #[test]
+    fn no_choices_is_an_error() {
+        let error = parse(json!({"choices": [], "usage": {"prompt_tokens": 9}})).unwrap_err();
+        assert!(matches!(error, Error::Provider(_)), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn stream_with_text_and_tool_calls() {
+        let (response, texts) = parse_stream(&[
+            json!({"choices": [{"index": 0, "delta": {"role": "assistant", "content": ""}, "finish_reason": null}], "usage": null}),
+            json!({"choices": [{"index": 0, "delta": {"content": "Checking "}, "finish_reason": null}], "usage": null}),
+            json!({"choices": [{"index": 0, "delta": {"content": "the file."}, "finish_reason": null}], "usage": null}),
+            json!({"choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "id": "call_1", "type": "function", "function": {"name": "read_file", "arguments": ""}}]}, "finish_reason": null}], "usage": null}),
+            json!({"choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "function": {"arguments": "{\"path\":"}}]}, "finish_reason": null}], "usage": null}),
+            json!({"choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "function": {"arguments": "\"Cargo.toml\"}"}}]}, "finish_reason": null}], "usage": null}),
+            json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "tool_calls"}], "usage": null}),
+            json!({"choices": [], "usage": {"prompt_tokens": 20, "completion_tokens": 15, "completion_tokens_details": {"reasoning_tokens": 4}}}),
+        ])
+        .await;
+
+        assert_eq!(texts.concat(), "Checking the file.");
+        assert_eq!(response.content, "Checking the file.");
+        assert_eq!(response.tool_calls.len(), 1);
+        assert_eq!(response.tool_calls[0].id, "call_1");
+        assert_eq!(
+            response.tool_calls[0].arguments,
+            json!({"path": "Cargo.toml"})
+        );
+        assert_eq!(
+            response.segments,
+            [
+                Segment::Text("Checking the file.".to_string()),
+                Segment::ToolUse(response.tool_calls[0].clone()),
+            ]
+        );
+        assert_eq!(response.finish_reason, Some(FinishReason::ToolCalls));
+        assert_eq!(response.usage.output_tokens, Some(15));
+        assert_eq!(response.usage.reasoning_tokens, Some(4));
+    }
 }
@@ -1,24 +1,230 @@
-use crate::providers::{AiProvider, AiResponse};
-use anyhow::Result;
-use reqwest::Client;
+use crate::providers::stream::{read_lines, sse_data};
+use crate::providers::tools::ToolCallStream;
+use crate::providers::{
+    self, AiProvider, AiResponse, ChatMessage, ChatRequest, Chunk, ChunkHandler, FinishReason,
+    ProviderBuilder, ProviderConfig, Segment, Thinking, ToolDefinition,
+};
+use crate::providers::{http, retry};
+use crate::{Error, Result, Setting};
+use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize)]
 struct OpenRouterRequest {
     model: String,
     messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    /// Number of choices to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning: Option<Reasoning>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+/// OpenRouter takes either an effort level or a token budget.
+#[derive(Serialize)]
+struct Reasoning {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effort: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+
+impl From<Thinking> for Reasoning {
+    fn from(thinking: Thinking) -> Self {
+        match thinking {
+            Thinking::Budget(tokens) => Reasoning {
+                effort: None,
+                max_tokens: Some(tokens),
+            },
+            level => Reasoning {
+                effort: Some(level.effort()),
+                max_tokens: None,
+            },
+        }
+    }
 }
 
 #[derive(Serialize)]
 struct Message {
     role: String,
-    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<Content>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+impl Message {
+    fn system(content: &str) -> Self {
+        Self {
+            role: "system".to_string(),
+            content: Some(Content::Text(content.to_string())),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
+impl From<&ChatMessage> for Message {
+    fn from(message: &ChatMessage) -> Self {
+        Self {
+            role: message.role.as_str().to_string(),
+            content: Content::new(message),
+            tool_calls: message.tool_calls.iter().map(ToolCall::from).collect(),
+            tool_call_id: message.tool_call_id.clone(),
+        }
+    }
+}
+
+/// Plain text, or a list of parts when the message has images.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize)]
+struct ImageUrl {
+    url: String,
+}
+
+impl Content {
+    fn new(message: &ChatMessage) -> Option<Self> {
+        if message.images.is_empty() {
+            // An assistant turn that only calls tools has no content.
+            return (message.tool_calls.is_empty() || !message.content.is_empty())
+                .then(|| Content::Text(message.content.clone()));
+        }
+
+        let text = (!message.content.is_empty()).then(|| ContentPart::Text {
+            text: message.content.clone(),
+        });
+        let images = message.images.iter().map(|image| ContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: image.data_url(),
+            },
+        });
+        Some(Content::Parts(text.into_iter().chain(images).collect()))
+    }
+}
+
+#[derive(Serialize)]
+struct Tool {
+    #[serde(rename = "type")]
+    kind: String,
+    function: Function,
+}
+
+#[derive(Serialize)]
+struct Function {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+}
+
+impl From<&ToolDefinition> for Tool {
+    fn from(tool: &ToolDefinition) -> Self {
+        Self {
+            kind: "function".to_string(),
+            function: Function {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                parameters: tool.parameters.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    function: FunctionCall,
+}
+
+/// `arguments` is a JSON object encoded as a string.
+#[derive(Serialize, Deserialize)]
+struct FunctionCall {
+    name: String,
+    arguments: String,
+}
+
+impl From<&providers::ToolCall> for ToolCall {
+    fn from(call: &providers::ToolCall) -> Self {
+        Self {
+            id: call.id.clone(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: call.name.clone(),
+                arguments: call.arguments.to_string(),
+            },
+        }
+    }
+}
+
+impl From<ToolCall> for providers::ToolCall {
+    fn from(call: ToolCall) -> Self {
+        Self::new(call.id, call.function.name, &call.function.arguments)
+    }
+}
+
+#[derive(Serialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    kind: String,
+    json_schema: JsonSchema,
+}
+
+#[derive(Serialize)]
+struct JsonSchema {
+    name: String,
+    schema: serde_json::Value,
+    /// Makes the API enforce the schema rather than treat it as a hint.
+    strict: bool,
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
 }
 
 #[derive(Deserialize)]
 struct MessageResponse {
-    content: String,
+    content: Option<String>,
+    /// Reasoning of thinking models.
+    reasoning: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
 }
 
 #[derive(Deserialize)]
@@ -30,90 +236,294 @@ struct OpenRouterResponse {
 #[derive(Deserialize)]
 struct Choice {
     message: MessageResponse,
+    finish_reason: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct Usage {
-    total_tokens: u32,
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    prompt_tokens_details: Option<PromptTokensDetails>,
+    completion_tokens_details: Option<CompletionTokensDetails>,
 }
 
+#[derive(Deserialize)]
+struct PromptTokensDetails {
+    cached_tokens: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct CompletionTokensDetails {
+    reasoning_tokens: Option<u32>,
+}
+
+impl From<Usage> for providers::Usage {
+    fn from(usage: Usage) -> Self {
+        Self {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+            cached_tokens: usage.prompt_tokens_details.and_then(|d| d.cached_tokens),
+            reasoning_tokens: usage
+                .completion_tokens_details
+                .and_then(|d| d.reasoning_tokens),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StreamChunk {
+    choices: Vec<StreamChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: Delta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    content: Option<String>,
+    /// Reasoning of thinking models.
+    reasoning: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallDelta>,
+}
+
+#[derive(Deserialize)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionDelta>,
+}
+
+#[derive(Deserialize)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+const DEFAULT_BASE_URL: &str = "https://openrouter.ai/api/v1";
+
 pub struct OpenRouterProvider {
     client: Client,
+    max_retries: u32,
+    stream_idle: Option<Duration>,
     api_key: String,
+    base_url: String,
     model: String,
 }
 
 impl OpenRouterProvider {
-    pub fn new(model: String) -> Result<Self> {
-        let api_key = std::env::var("OPENROUTER_API_KEY")
-            .map_err(|_| anyhow::anyhow!("OPENROUTER_API_KEY environment variable not set"))?;
-
-        let model = if model == "DEFAULT" {
-            "google/gemini-2.5-flash".to_string()
-        } else {
-            model
-        };
+    /// Sets up the provider from explicit settings; the API key must be set.
+    pub fn builder() -> ProviderBuilder<Self> {
+        ProviderBuilder::new("openrouter", Self::new)
+    }
+
+    pub fn new(config: &ProviderConfig) -> Result<Self> {
+        let api_key = config.api_key.clone().ok_or_else(|| Error::Missing {
+            provider: "openrouter".to_string(),
+            setting: Setting::ApiKey,
+        })?;
+
+        let model = config
+            .model
+            .clone()
+            .unwrap_or_else(|| "google/gemini-2.5-flash".to_string());
+
+        let base_url = config
+            .base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+            .to_string();
 
         Ok(Self {
-            client: Client::new(),
+            client: http::client(&config.timeouts)?,
+            max_retries: config.max_retries.unwrap_or(retry::DEFAULT_MAX_RETRIES),
+            stream_idle: config.timeouts.stream_idle()?,
             api_key,
+            base_url,
             model,
         })
     }
+
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<(Response, u32)> {
+        let request_body = OpenRouterRequest {
+            model: self.model.clone(),
+            messages: request
+                .system
+                .iter()
+                .map(|system| Message::system(system))
+                .chain(request.messages.iter().map(Message::from))
+                .collect(),
+            temperature: request.options.temperature,
+            top_p: request.options.top_p,
+            top_k: request.options.top_k,
+            max_tokens: request.options.max_tokens,
+            stop: request.options.stop.clone(),
+            seed: request.options.seed,
+            n: request.options.candidates,
+            reasoning: request.options.thinking.map(Reasoning::from),
+            tools: request.tools.iter().map(Tool::from).collect(),
+            response_format: request.schema.as_ref().map(|schema| ResponseFormat {
+                kind: "json_schema".to_string(),
+                json_schema: JsonSchema {
+                    name: "response".to_string(),
+                    schema: schema.clone(),
+                    strict: request.strict_schema(),
+                },
+            }),
+            stream,
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+        };
+
+        let (response, retries) = retry::send(
+            self.client
+                .post(format!("{}/chat/completions", self.base_url))
+                .header("Authorization", format!("Bearer {}", self.api_key))
+                .header("Content-Type", "application/json")
+                .json(&request_body),
+            self.max_retries,
+            warnings,
+        )
+        .await?;
+
+        let response = http::check_status(response, "OpenRouter API").await?;
+
+        Ok((response, retries))
+    }
 }
 
 #[async_trait::async_trait]
 impl AiProvider for OpenRouterProvider {
-    async fn query(&self, prompt: &str) -> Result<AiResponse> {
+    async fn query(&self, request: &ChatRequest) -> Result<AiResponse> {
         let start = Instant::now();
 
-        let request_body = OpenRouterRequest {
-            model: self.model.clone(),
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: prompt.to_string(),
-            }],
-        };
-
-        let response = self
-            .client
-            .post("https://openrouter.ai/api/v1/chat/completions")
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .await?;
+        let mut warnings = Vec::new();
+        let (response, retries) = self.send(request, false, &mut warnings).await?;
 
         let duration = start.elapsed();
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await?;
-            anyhow::bail!(
-                "OpenRouter API request failed with status {}: {}",
-                status,
-                text
-            );
-        }
+        let openrouter_response: OpenRouterResponse = http::json(response).await?;
 
-        let openrouter_response: OpenRouterResponse = response.json().await?;
+        let mut choices = openrouter_response.choices.into_iter();
+        let choice = choices
+            .next()
+            .ok_or_else(|| Error::Provider("No response from OpenRouter".to_string()))?;
+        let alternatives = choices
+            .map(|choice| choice.message.content.unwrap_or_default())
+            .collect();
+        let message = choice.message;
 
-        let content = openrouter_response
-            .choices
-            .first()
-            .ok_or_else(|| anyhow::anyhow!("No response from OpenRouter"))?
-            .message
-            .content
-            .clone();
+        let content = message.content.unwrap_or_default();
+        let tool_calls: Vec<providers::ToolCall> =
+            message.tool_calls.into_iter().map(Into::into).collect();
+        let segments = Segment::sequence(
+            message.reasoning.as_deref().unwrap_or(""),
+            &content,
+            &tool_calls,
+        );
 
-        let tokens_used = openrouter_response.usage.map(|u| u.total_tokens);
+        let usage = openrouter_response
+            .usage
+            .map(Into::into)
+            .unwrap_or_default();
 
-        Ok(AiResponse {
+        AiResponse {
             content,
-            tokens_used,
+            tool_calls,
+            segments,
+            alternatives,
+            reasoning: Vec::new(),
+            finish_reason: choice.finish_reason.as_deref().map(FinishReason::parse),
+            usage,
             duration,
             model: self.model.clone(),
             provider: "openrouter".to_string(),
+            retries,
+            failures: Vec::new(),
+            warnings,
+        }
+        .check_refusal("OpenRouter")
+    }
+
+    async fn query_stream(
+        &self,
+        request: &ChatRequest,
+        on_chunk: &mut ChunkHandler<'_>,
+    ) -> Result<AiResponse> {
+        let start = Instant::now();
+
+        let mut warnings = Vec::new();
+        let (response, retries) = self.send(request, true, &mut warnings).await?;
+
+        let mut content = String::new();
+        let mut thinking = String::new();
+        let mut tool_calls = ToolCallStream::default();
+        let mut finish_reason = None;
+        let mut usage = providers::Usage::default();
+
+        read_lines(response, self.stream_idle, |line| {
+            let Some(data) = sse_data(line) else {
+                return Ok(());
+            };
+            let chunk: StreamChunk = serde_json::from_str(data)?;
+            if let Some(choice) = chunk.choices.first() {
+                if let Some(text) = choice.delta.reasoning.as_deref() {
+                    on_chunk(Chunk::Thinking(text));
+                    thinking.push_str(text);
+                }
+                if let Some(text) = choice.delta.content.as_deref() {
+                    on_chunk(Chunk::Text(text));
+                    content.push_str(text);
+                }
+                for call in &choice.delta.tool_calls {
+                    let function = call.function.as_ref();
+                    tool_calls.update(
+                        call.index,
+                        call.id.as_deref(),
+                        function.and_then(|f| f.name.as_deref()),
+                        function.and_then(|f| f.arguments.as_deref()),
+                    );
+                }
+                if let Some(reason) = choice.finish_reason.as_deref() {
+                    finish_reason = Some(FinishReason::parse(reason));
+                }
+            }
+            if let Some(chunk_usage) = chunk.usage {
+                usage = chunk_usage.into();
+            }
+            Ok(())
         })
+        .await?;
+
+        let tool_calls = tool_calls.finish();
+        let segments = Segment::sequence(&thinking, &content, &tool_calls);
+
+        AiResponse {
+            content,
+            tool_calls,
+            segments,
+            alternatives: Vec::new(),
+            reasoning: Vec::new(),
+            finish_reason,
+            usage,
+            duration: start.elapsed(),
+            model: self.model.clone(),
+            provider: "openrouter".to_string(),
+            retries,
+            failures: Vec::new(),
+            warnings,
+        }
+        .check_refusal("OpenRouter")
     }
 }
@@ -0,0 +1,209 @@
+use crate::{Error, Result};
+use reqwest::header::HeaderMap;
+use reqwest::{RequestBuilder, Response};
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+const BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Upper bound for a single wait, including one asked for by `Retry-After`.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Sends `request`, retrying connection errors and transient statuses (408,
+/// 429, 5xx and Anthropic's 529 "overloaded") up to `max_retries` times.
+/// Waits as long as `Retry-After` asks when the server sends it, else backs
+/// off exponentially with jitter. Returns the final response, which may still
+/// be an error status, together with the number of retries made; each retry
+/// is described in `warnings`.
+pub async fn send(
+    request: RequestBuilder,
+    max_retries: u32,
+    warnings: &mut Vec<String>,
+) -> Result<(Response, u32)> {
+    let mut retries = 0;
+
+    loop {
+        let attempt = request
+            .try_clone()
+            .ok_or_else(|| Error::Provider("Request cannot be retried".to_string()))?;
+
+        let (delay, reason) = match attempt.send().await {
+            Ok(response)
+                if retries < max_retries && is_retriable_status(response.status().as_u16()) =>
+            {
+                (
+                    retry_after(response.headers()).unwrap_or_else(|| backoff(retries)),
+                    format!("status {}", response.status().as_u16()),
+                )
+            }
+            Ok(response) => return Ok((response, retries)),
+            Err(e) if retries < max_retries && (e.is_connect() || e.is_timeout()) => {
+                (backoff(retries), format!("{:#}", Error::from(e)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        retries += 1;
+        warnings.push(format!(
+            "request failed with {}, retrying in {:.1}s ({}/{})",
+            reason,
+            delay.as_secs_f64(),
+            retries,
+            max_retries
+        ));
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Whether `error` is a transient failure that the same request might not hit
+/// again: a retriable status, a connection error or a timeout.
+pub fn is_retriable(error: &Error) -> bool {
+    match error.root() {
+        Error::Network(e) => e.is_connect(),
+        Error::Timeout(_) => true,
+        _ => error.status().is_some_and(is_retriable_status),
+    }
+}
+
+fn is_retriable_status(status: u16) -> bool {
+    matches!(status, 408 | 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+/// Reads `retry-after-ms` (sent by OpenAI) or `Retry-After`, given either in
+/// seconds or as an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name)?.to_str().ok();
+
+    let delay = if let Some(ms) = header("retry-after-ms").and_then(|v| v.parse::<f64>().ok()) {
+        Duration::try_from_secs_f64(ms.max(0.0) / 1000.0).ok()?
+    } else {
+        let value = header("retry-after")?;
+        match value.parse::<f64>() {
+            Ok(seconds) => Duration::try_from_secs_f64(seconds.max(0.0)).ok()?,
+            Err(_) => {
+                let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+                (date.to_utc() - chrono::Utc::now())
+                    .to_std()
+                    .unwrap_or_default()
+            }
+        }
+    };
+
+    Some(delay.min(MAX_DELAY))
+}
+
+/// Exponential backoff with jitter: a random wait between half and all of
+/// `BASE_DELAY * 2^retries`, capped at `MAX_DELAY`.
+fn backoff(retries: u32) -> Duration {
+    let delay = BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(retries))
+        .min(MAX_DELAY);
+
+    // RandomState is seeded randomly per instance, which is all the
+    // randomness jitter needs.
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    let fraction = 0.5 + (random % 1000) as f64 / 2000.0;
+
+    delay.mul_f64(fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ApiError;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn api_error(status: u16) -> Error {
+        ApiError {
+            api: "Test API".to_string(),
+            status,
+            body: String::new(),
+        }
+        .into_error()
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "7")])),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "1.5")])),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "-3")])),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        let delay = retry_after(&headers(&[("retry-after", &date)])).unwrap();
+        assert!(delay > Duration::from_secs(28) && delay <= Duration::from_secs(30));
+
+        let past = (chrono::Utc::now() - chrono::Duration::seconds(30)).to_rfc2822();
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", &past)])),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn retry_after_ms_wins() {
+        assert_eq!(
+            retry_after(&headers(&[("retry-after-ms", "250"), ("retry-after", "7")])),
+            Some(Duration::from_millis(250))
+        );
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "3600")])),
+            Some(MAX_DELAY)
+        );
+    }
+
+    #[test]
+    fn retry_after_missing_or_invalid() {
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after(&headers(&[("retry-after", "soon")])), None);
+    }
+
+    #[test]
+    fn backoff_grows_within_jitter() {
+        for retries in 0..4 {
+            let full = BASE_DELAY * 2u32.pow(retries);
+            let delay = backoff(retries);
+            assert!(
+                delay >= full / 2 && delay <= full,
+                "{delay:?} for {retries}"
+            );
+        }
+        assert!(backoff(u32::MAX) <= MAX_DELAY);
+    }
+
+    #[test]
+    fn retriable_errors() {
+        assert!(is_retriable(&api_error(429)));
+        assert!(is_retriable(&api_error(503)));
+        assert!(is_retriable(&api_error(529)));
+        assert!(is_retriable(&Error::Timeout("Stream stalled".to_string())));
+        assert!(!is_retriable(&api_error(400)));
+        assert!(!is_retriable(&api_error(401)));
+        assert!(!is_retriable(&Error::Provider("No response".to_string())));
+    }
+}
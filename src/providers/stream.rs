@@ -0,0 +1,147 @@
+use crate::{Error, Result};
+use reqwest::Response;
+use std::time::Duration;
+
+/// Reads a chunked response body line by line, buffering partial lines that
+/// span chunk boundaries. Used for both SSE and NDJSON streams. Fails when no
+/// data arrives for `idle`.
+pub async fn read_lines(
+    mut response: Response,
+    idle: Option<Duration>,
+    mut on_line: impl FnMut(&str) -> Result<()>,
+) -> Result<()> {
+    let mut lines = Lines::default();
+
+    loop {
+        let chunk = match idle {
+            Some(idle) => tokio::time::timeout(idle, response.chunk())
+                .await
+                .map_err(|_| {
+                    Error::Timeout(format!(
+                        "Stream stalled: no data received for {}s",
+                        idle.as_secs_f64()
+                    ))
+                })??,
+            None => response.chunk().await?,
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+
+        lines.push(&chunk, &mut on_line)?;
+    }
+
+    lines.finish(on_line)
+}
+
+/// Splits chunks into non-empty lines, keeping an unfinished line until the
+/// rest of it arrives. Lines are only decoded once complete, so multi-byte
+/// characters split across chunks stay intact.
+#[derive(Debug, Default)]
+struct Lines {
+    buffer: Vec<u8>,
+}
+
+impl Lines {
+    fn push(&mut self, chunk: &[u8], on_line: &mut impl FnMut(&str) -> Result<()>) -> Result<()> {
+        self.buffer.extend_from_slice(chunk);
+
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if !line.is_empty() {
+                on_line(line)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Passes on the last line when the body does not end with a newline.
+    fn finish(self, mut on_line: impl FnMut(&str) -> Result<()>) -> Result<()> {
+        let rest = String::from_utf8_lossy(&self.buffer);
+        let rest = rest.trim();
+        if !rest.is_empty() {
+            on_line(rest)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Extracts the payload of an SSE `data:` line. Returns `None` for comments,
+/// other fields and the OpenAI-style `[DONE]` terminator.
+pub fn sse_data(line: &str) -> Option<&str> {
+    let data = line.strip_prefix("data:")?.trim_start();
+    if data == "[DONE]" { None } else { Some(data) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(chunks: &[&[u8]]) -> Vec<String> {
+        let mut found = Vec::new();
+        let mut on_line = |line: &str| {
+            found.push(line.to_string());
+            Ok(())
+        };
+
+        let mut lines = Lines::default();
+        for chunk in chunks {
+            lines.push(chunk, &mut on_line).unwrap();
+        }
+        lines.finish(on_line).unwrap();
+
+        found
+    }
+
+    #[test]
+    fn lines_split_across_chunks() {
+        assert_eq!(
+            split(&[b"data: {\"a\":", b"1}\n\ndata: {\"b\"", b":2}\n"]),
+            ["data: {\"a\":1}", "data: {\"b\":2}"]
+        );
+    }
+
+    #[test]
+    fn crlf_split_between_chunks() {
+        assert_eq!(
+            split(&[b"data: 1\r", b"\ndata: 2\r\n"]),
+            ["data: 1", "data: 2"]
+        );
+    }
+
+    #[test]
+    fn multibyte_character_split_between_chunks() {
+        let text = "data: \u{e9}t\u{e9}\n".as_bytes();
+        assert_eq!(split(&[&text[..7], &text[7..]]), ["data: \u{e9}t\u{e9}"]);
+    }
+
+    #[test]
+    fn last_line_without_newline() {
+        assert_eq!(
+            split(&[b"{\"done\":false}\n{\"done\"", b":true}"]),
+            ["{\"done\":false}", "{\"done\":true}"]
+        );
+    }
+
+    #[test]
+    fn callback_errors_stop_reading() {
+        let mut lines = Lines::default();
+        let error = lines.push(b"data: 1\n", &mut |_: &str| {
+            Err(Error::Provider("bad event".to_string()))
+        });
+        assert!(matches!(error, Err(Error::Provider(_))));
+    }
+
+    #[test]
+    fn sse_payloads() {
+        assert_eq!(sse_data("data: {\"a\":1}"), Some("{\"a\":1}"));
+        assert_eq!(sse_data("data:{}"), Some("{}"));
+        assert_eq!(sse_data("data: [DONE]"), None);
+        assert_eq!(sse_data("event: message_start"), None);
+        assert_eq!(sse_data(": keep-alive"), None);
+    }
+}
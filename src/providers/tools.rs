@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// A function the model may call, described the same way for every provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments object.
+    pub parameters: Value,
+}
+
+/// A call the model asked for. Providers that do not assign ids get
+/// `call_<n>` ids, numbered within the response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+impl ToolCall {
+    /// Parses arguments sent as a JSON string. Malformed JSON is kept as a
+    /// string so the tool can report it back to the model.
+    pub fn new(id: String, name: String, arguments: &str) -> Self {
+        let arguments = if arguments.trim().is_empty() {
+            Value::Object(Default::default())
+        } else {
+            serde_json::from_str(arguments).unwrap_or_else(|_| Value::String(arguments.to_string()))
+        };
+
+        Self {
+            id,
+            name,
+            arguments,
+        }
+    }
+}
+
+/// Collects tool calls whose id, name and arguments arrive in pieces while
+/// streaming, keyed by the index the provider gives each call.
+#[derive(Debug, Default)]
+pub struct ToolCallStream {
+    calls: BTreeMap<usize, (String, String, String)>,
+}
+
+impl ToolCallStream {
+    pub fn update(
+        &mut self,
+        index: usize,
+        id: Option<&str>,
+        name: Option<&str>,
+        arguments: Option<&str>,
+    ) {
+        let (call_id, call_name, call_arguments) = self.calls.entry(index).or_default();
+        // Ids and names come whole in one event; only the arguments are split.
+        if let Some(id) = id.filter(|_| call_id.is_empty()) {
+            *call_id = id.to_string();
+        }
+        if let Some(name) = name.filter(|_| call_name.is_empty()) {
+            *call_name = name.to_string();
+        }
+        call_arguments.push_str(arguments.unwrap_or(""));
+    }
+
+    pub fn finish(self) -> Vec<ToolCall> {
+        self.calls
+            .into_iter()
+            .map(|(index, (id, name, arguments))| {
+                let id = if id.is_empty() {
+                    format!("call_{}", index)
+                } else {
+                    id
+                };
+                ToolCall::new(id, name, &arguments)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn arguments_assembled_from_fragments() {
+        let mut stream = ToolCallStream::default();
+        stream.update(0, Some("call_abc"), Some("read_file"), Some(""));
+        stream.update(0, None, None, Some("{\"pa"));
+        stream.update(0, None, None, Some("th\": \"src/"));
+        stream.update(0, None, None, Some("main.rs\"}"));
+
+        let calls = stream.finish();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_abc");
+        assert_eq!(calls[0].name, "read_file");
+        assert_eq!(calls[0].arguments, json!({"path": "src/main.rs"}));
+    }
+
+    #[test]
+    fn interleaved_calls_ordered_by_index() {
+        let mut stream = ToolCallStream::default();
+        stream.update(1, Some("call_2"), Some("current_time"), None);
+        stream.update(
+            0,
+            Some("call_1"),
+            Some("calculator"),
+            Some("{\"expression\":"),
+        );
+        stream.update(1, None, None, Some("{}"));
+        stream.update(0, None, None, Some("\"2+2\"}"));
+
+        let calls = stream.finish();
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].arguments, json!({"expression": "2+2"}));
+        assert_eq!(calls[1].id, "call_2");
+        assert_eq!(calls[1].arguments, json!({}));
+    }
+
+    #[test]
+    fn repeated_ids_and_names_are_not_appended() {
+        let mut stream = ToolCallStream::default();
+        stream.update(0, Some("call_1"), Some("calculator"), Some("{"));
+        stream.update(0, Some("call_1"), Some("calculator"), Some("}"));
+
+        let calls = stream.finish();
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].name, "calculator");
+        assert_eq!(calls[0].arguments, json!({}));
+    }
+
+    #[test]
+    fn missing_id_is_made_up() {
+        let mut stream = ToolCallStream::default();
+        stream.update(3, None, Some("current_time"), None);
+
+        let calls = stream.finish();
+        assert_eq!(calls[0].id, "call_3");
+        assert_eq!(calls[0].arguments, json!({}));
+    }
+
+    #[test]
+    fn malformed_arguments_kept_as_string() {
+        let call = ToolCall::new("call_1".into(), "calculator".into(), "{\"expression\": 2+");
+        assert_eq!(call.arguments, json!("{\"expression\": 2+"));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::output::{Delivery, OutputFormat};
+use crate::toolbox::{self, Toolbox};
+use anyhow::Result;
+use clm::providers::{AiProvider, AiResponse, ChatMessage, ChatRequest};
+use serde_json::Value;
+use std::path::Path;
+use std::time::Duration;
+
+/// Answers checked against the schema before giving up. Later attempts tell
+/// the model what was wrong with the previous answer.
+const MAX_ATTEMPTS: usize = 3;
+
+/// A JSON schema loaded with `--schema`.
+pub struct Schema {
+    value: Value,
+    validator: jsonschema::Validator,
+}
+
+impl Schema {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read schema from {}: {}", path.display(), e))?;
+        let value: Value = serde_json::from_str(&text)
+            .map_err(|e| anyhow::anyhow!("Invalid JSON in {}: {}", path.display(), e))?;
+        let validator = jsonschema::validator_for(&value)
+            .map_err(|e| anyhow::anyhow!("Invalid schema in {}: {}", path.display(), e))?;
+
+        Ok(Self { value, validator })
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// Returns the JSON in `content`, or a description of why it does not
+    /// match. Code fences around the JSON are removed.
+    fn check(&self, content: &str) -> Result<String, String> {
+        let json = strip_fences(content);
+
+        let value: Value = serde_json::from_str(json)
+            .map_err(|e| format!("The answer is not valid JSON: {}", e))?;
+
+        let errors: Vec<String> = self
+            .validator
+            .iter_errors(&value)
+            .map(|error| {
+                let path = error.instance_path().to_string();
+                format!("- {}: {}", if path.is_empty() { "/" } else { &path }, error)
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(json.to_string())
+        } else {
+            Err(format!(
+                "The answer does not match the schema:\n{}",
+                errors.join("\n")
+            ))
+        }
+    }
+}
+
+/// Sends the request until the answer matches the schema and prints it. Each
+/// failed attempt is shown to the model so it can correct itself. The
+/// returned response holds the bare JSON and the usage of all attempts.
+pub async fn complete(
+    provider: &(dyn AiProvider + Send + Sync),
+    request: &mut ChatRequest,
+    toolbox: &Toolbox,
+    schema: &Schema,
+    delivery: Delivery,
+) -> Result<AiResponse> {
+    let mut usage = Default::default();
+    let mut duration = Duration::ZERO;
+    let mut retries = 0;
+
+    let mut attempt = 1;
+
+    loop {
+        // Nothing is printed until the answer has been validated.
+        let silent = Delivery {
+            format: OutputFormat::Json,
+            no_stream: true,
+            ..delivery
+        };
+        let response = toolbox::complete(provider, request, toolbox, silent).await?;
+        usage = response.usage.add(&usage);
+        duration += response.duration;
+        retries += response.retries;
+
+        let problem = match schema.check(&response.content) {
+            Ok(json) => {
+                if delivery.format == OutputFormat::Text {
+                    println!("{}", json);
+                }
+                return Ok(AiResponse {
+                    content: json,
+                    usage,
+                    duration,
+                    retries,
+                    ..response
+                });
+            }
+            Err(problem) => problem,
+        };
+
+        if attempt == MAX_ATTEMPTS {
+            anyhow::bail!("{} (after {} attempts)", problem, MAX_ATTEMPTS);
+        }
+        eprintln!("Warning: {}\nAsking again.", problem);
+        attempt += 1;
+
+        request
+            .messages
+            .push(ChatMessage::assistant(response.content));
+        request.messages.push(ChatMessage::user(format!(
+            "{}\nReply again with only the corrected JSON.",
+            problem
+        )));
+    }
+}
+
+/// Removes a Markdown code fence around the answer, e.g. ```json ... ```.
+fn strip_fences(content: &str) -> &str {
+    let content = content.trim();
+
+    let Some(inner) = content
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+    else {
+        return content;
+    };
+
+    // Drop the language tag on the opening line.
+    match inner.split_once('\n') {
+        Some((_, body)) => body.trim(),
+        None => inner.trim(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_json_untouched() {
+        assert_eq!(strip_fences(" {\"a\": 1}\n"), "{\"a\": 1}");
+    }
+
+    #[test]
+    fn fences_with_language_tag() {
+        assert_eq!(strip_fences("```json\n{\"a\": 1}\n```"), "{\"a\": 1}");
+    }
+
+    #[test]
+    fn fences_without_language_tag() {
+        assert_eq!(strip_fences("```\n[1, 2]\n```\n"), "[1, 2]");
+        assert_eq!(strip_fences("```{}```"), "{}");
+    }
+
+    #[test]
+    fn unclosed_fence_untouched() {
+        assert_eq!(strip_fences("```json\n{}"), "```json\n{}");
+    }
+}
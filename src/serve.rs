@@ -0,0 +1,703 @@
+use crate::config::{self, Config, Profile, Selection};
+use crate::ledger;
+use crate::output;
+use crate::pricing::Pricing;
+use anyhow::Result;
+use chrono::Utc;
+use clm::providers::{
+    self, AiResponse, ChatMessage, ChatRequest, Chunk, GenerationOptions, Image, ProviderConfig,
+    Role, ToolCall, ToolDefinition,
+};
+use http_body_util::channel::Channel;
+use http_body_util::{BodyExt, Either, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Largest request body accepted, enough for a few images.
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+
+type Body = Either<Full<Bytes>, Channel<Bytes>>;
+
+#[derive(Deserialize)]
+struct CompletionRequest {
+    #[serde(default)]
+    model: String,
+    messages: Vec<Message>,
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<u32>,
+    max_tokens: Option<u32>,
+    max_completion_tokens: Option<u32>,
+    stop: Option<Stop>,
+    seed: Option<u64>,
+    n: Option<u32>,
+    reasoning_effort: Option<String>,
+    #[serde(default)]
+    tools: Vec<Tool>,
+    response_format: Option<ResponseFormat>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct Message {
+    role: String,
+    content: Option<Content>,
+    #[serde(default)]
+    tool_calls: Vec<MessageToolCall>,
+    tool_call_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text {
+        text: String,
+    },
+    ImageUrl {
+        image_url: ImageUrl,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct ImageUrl {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct MessageToolCall {
+    id: String,
+    function: FunctionCall,
+}
+
+#[derive(Deserialize)]
+struct FunctionCall {
+    name: String,
+    #[serde(default)]
+    arguments: String,
+}
+
+#[derive(Deserialize)]
+struct Tool {
+    function: Function,
+}
+
+#[derive(Deserialize)]
+struct Function {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default = "empty_object")]
+    parameters: Value,
+}
+
+fn empty_object() -> Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+#[derive(Deserialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    kind: String,
+    json_schema: Option<JsonSchema>,
+}
+
+#[derive(Deserialize)]
+struct JsonSchema {
+    schema: Value,
+}
+
+#[derive(Serialize)]
+struct Completion<'a> {
+    id: &'a str,
+    object: &'static str,
+    created: i64,
+    model: &'a str,
+    choices: Vec<Choice>,
+    usage: Option<UsageReport>,
+}
+
+#[derive(Serialize)]
+struct Choice {
+    index: usize,
+    message: ResponseMessage,
+    finish_reason: String,
+}
+
+#[derive(Serialize)]
+struct ResponseMessage {
+    role: &'static str,
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ResponseToolCall>,
+}
+
+#[derive(Serialize)]
+struct ResponseToolCall {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+    id: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: ResponseFunction,
+}
+
+#[derive(Serialize)]
+struct ResponseFunction {
+    name: String,
+    arguments: String,
+}
+
+impl ResponseToolCall {
+    fn new(call: &ToolCall, index: Option<usize>) -> Self {
+        Self {
+            index,
+            id: call.id.clone(),
+            kind: "function",
+            function: ResponseFunction {
+                name: call.name.clone(),
+                arguments: call.arguments.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UsageReport {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+    total_tokens: u32,
+}
+
+impl From<&AiResponse> for UsageReport {
+    fn from(response: &AiResponse) -> Self {
+        let usage = &response.usage;
+        Self {
+            prompt_tokens: usage.input_tokens.unwrap_or(0),
+            completion_tokens: usage.output_tokens.unwrap_or(0),
+            total_tokens: usage.total_tokens().unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CompletionChunk<'a> {
+    id: &'a str,
+    object: &'static str,
+    created: i64,
+    model: &'a str,
+    choices: Vec<ChunkChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<UsageReport>,
+}
+
+#[derive(Serialize)]
+struct ChunkChoice {
+    index: usize,
+    delta: Delta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ResponseToolCall>,
+}
+
+/// Settings shared by every request the server handles.
+struct Server {
+    config: Config,
+    profile: Profile,
+    /// Retries and timeouts from the command line.
+    settings: Selection,
+    /// Generation options from the command line and the profile, used where
+    /// a request does not set its own.
+    options: GenerationOptions,
+    pricing: Pricing,
+}
+
+/// Serves an OpenAI-compatible API on `127.0.0.1:port`, routing each request
+/// to the provider its model name maps to. Answers are recorded in the usage
+/// ledger.
+pub async fn run(
+    config: Config,
+    profile: Profile,
+    settings: Selection,
+    options: GenerationOptions,
+    pricing: Pricing,
+    port: u16,
+) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen on port {}: {}", port, e))?;
+    eprintln!("clm serve listening on http://127.0.0.1:{}/v1", port);
+
+    let server = Arc::new(Server {
+        config,
+        profile,
+        settings,
+        options,
+        pricing,
+    });
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Warning: failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+
+        let server = server.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let server = server.clone();
+                async move { Ok::<_, Infallible>(server.handle(request).await) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                eprintln!("Warning: connection failed: {}", e);
+            }
+        });
+    }
+}
+
+impl Server {
+    async fn handle(self: Arc<Self>, request: Request<Incoming>) -> Response<Body> {
+        let path = request.uri().path().trim_end_matches('/');
+        match (request.method(), path) {
+            (&Method::GET, "/v1/models") => self.models(),
+            (&Method::POST, "/v1/chat/completions") => {
+                self.complete(request).await.unwrap_or_else(|e| {
+                    eprintln!("Error: {:#}", e);
+                    error_response(&e)
+                })
+            }
+            (_, "/v1/models" | "/v1/chat/completions") => {
+                error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+            }
+            _ => error(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    /// Lists the profiles and providers that can be used as model names.
+    fn models(&self) -> Response<Body> {
+        let profiles = self.config.profiles.iter().map(|(name, profile)| {
+            let provider = profile
+                .provider
+                .as_deref()
+                .unwrap_or(config::DEFAULT_PROVIDER);
+            (name.as_str(), provider)
+        });
+        let providers = providers::PROVIDERS.iter().map(|name| (*name, *name));
+
+        let data: Vec<Value> = profiles
+            .chain(providers)
+            .map(|(id, owner)| json!({ "id": id, "object": "model", "created": 0, "owned_by": owner }))
+            .collect();
+        json_response(StatusCode::OK, &json!({ "object": "list", "data": data }))
+    }
+
+    /// The provider a model name maps to: a profile of that name, a
+    /// `provider` or `provider:model` spec as `clm compare` takes, or else a
+    /// model of the selected profile's provider.
+    fn route(&self, model: &str) -> Result<ProviderConfig> {
+        if let Some(profile) = self.config.profiles.get(model) {
+            return profile.provider_config(&self.settings);
+        }
+
+        let (provider, name) = config::parse_spec(model);
+        if providers::PROVIDERS.contains(&provider.to_lowercase().as_str()) {
+            return self.profile.provider_config(&Selection {
+                provider: Some(provider),
+                model: name,
+                ..self.settings.clone()
+            });
+        }
+
+        self.profile.provider_config(&Selection {
+            model: (!model.is_empty()).then(|| model.to_string()),
+            ..self.settings.clone()
+        })
+    }
+
+    async fn complete(self: Arc<Self>, request: Request<Incoming>) -> Result<Response<Body>> {
+        let body = Limited::new(request.into_body(), MAX_BODY_BYTES)
+            .collect()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read the request: {}", e))?
+            .to_bytes();
+        let completion: CompletionRequest =
+            serde_json::from_slice(&body).map_err(|e| anyhow::anyhow!("Invalid request: {}", e))?;
+
+        let config = self.route(&completion.model)?;
+        eprintln!("{} -> {}", completion.model, config.label());
+        let provider = providers::get_provider(&config)?;
+
+        let stream = completion.stream;
+        let model = completion.model.clone();
+        let request = self.chat_request(completion)?;
+        if stream && request.options.candidates.is_some_and(|n| n > 1) {
+            anyhow::bail!("n greater than 1 is not supported with stream");
+        }
+
+        let id = format!("chatcmpl-{}", Utc::now().timestamp_micros());
+        let created = Utc::now().timestamp();
+
+        if !stream {
+            let response = provider.query(&request).await?;
+            self.record(&response);
+            let completion = Completion {
+                id: &id,
+                object: "chat.completion",
+                created,
+                model: &model,
+                choices: choices(&response),
+                usage: Some(UsageReport::from(&response)),
+            };
+            return Ok(json_response(StatusCode::OK, &completion));
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let (mut sender, body) = Channel::<Bytes>::new(16);
+
+        let task = tokio::spawn(async move {
+            let event =
+                |delta: Delta, finish_reason: Option<String>, usage: Option<UsageReport>| {
+                    sse(&CompletionChunk {
+                        id: &id,
+                        object: "chat.completion.chunk",
+                        created,
+                        model: &model,
+                        choices: vec![ChunkChoice {
+                            index: 0,
+                            delta,
+                            finish_reason,
+                        }],
+                        usage,
+                    })
+                };
+
+            let role = Delta {
+                role: Some("assistant"),
+                content: Some(String::new()),
+                ..Default::default()
+            };
+            let _ = tx.send(event(role, None, None));
+
+            let mut on_chunk = |chunk: Chunk| {
+                let delta = match chunk {
+                    Chunk::Text(text) => Delta {
+                        content: Some(text.to_string()),
+                        ..Default::default()
+                    },
+                    Chunk::Thinking(text) => Delta {
+                        reasoning_content: Some(text.to_string()),
+                        ..Default::default()
+                    },
+                };
+                let _ = tx.send(event(delta, None, None));
+            };
+
+            match provider.query_stream(&request, &mut on_chunk).await {
+                Ok(response) => {
+                    self.record(&response);
+                    let delta = Delta {
+                        tool_calls: response
+                            .tool_calls
+                            .iter()
+                            .enumerate()
+                            .map(|(index, call)| ResponseToolCall::new(call, Some(index)))
+                            .collect(),
+                        ..Default::default()
+                    };
+                    let usage = Some(UsageReport::from(&response));
+                    let _ = tx.send(event(delta, Some(finish_reason(&response)), usage));
+                }
+                Err(e) => {
+                    eprintln!("Error: {:#}", e);
+                    let _ = tx.send(sse(&error_body(&e.into())));
+                }
+            }
+            let _ = tx.send("data: [DONE]\n\n".to_string());
+        });
+
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if sender.send_data(Bytes::from(event)).await.is_err() {
+                    // The client disconnected; stop generating the answer
+                    // rather than paying for the rest of it.
+                    task.abort();
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(Either::Right(body))?)
+    }
+
+    /// Translates an OpenAI request, falling back to the server's generation
+    /// options for settings it leaves out.
+    fn chat_request(&self, completion: CompletionRequest) -> Result<ChatRequest> {
+        let mut system = Vec::new();
+        let mut messages = Vec::new();
+
+        for message in completion.messages {
+            let (text, images) = content(message.content)?;
+            match message.role.as_str() {
+                "system" | "developer" => system.push(text),
+                "user" => messages.push(ChatMessage::user(text).with_images(images)),
+                "assistant" => {
+                    let calls = message
+                        .tool_calls
+                        .into_iter()
+                        .map(|call| {
+                            ToolCall::new(call.id, call.function.name, &call.function.arguments)
+                        })
+                        .collect();
+                    messages.push(ChatMessage::tool_calls(text, calls));
+                }
+                "tool" => messages.push(ChatMessage {
+                    role: Role::Tool,
+                    content: text,
+                    tool_calls: Vec::new(),
+                    tool_call_id: message.tool_call_id,
+                    images: Vec::new(),
+                    reasoning: Vec::new(),
+                }),
+                role => anyhow::bail!("Unsupported message role '{}'", role),
+            }
+        }
+
+        let thinking = completion
+            .reasoning_effort
+            .map(|effort| effort.parse())
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("reasoning_effort: {}", e))?;
+
+        let options = GenerationOptions {
+            temperature: completion.temperature,
+            top_p: completion.top_p,
+            top_k: completion.top_k,
+            max_tokens: completion.max_completion_tokens.or(completion.max_tokens),
+            stop: match completion.stop {
+                Some(Stop::One(stop)) => vec![stop],
+                Some(Stop::Many(stop)) => stop,
+                None => Vec::new(),
+            },
+            seed: completion.seed,
+            candidates: completion.n,
+            thinking,
+        };
+
+        let schema = completion
+            .response_format
+            .and_then(|format| match format.kind.as_str() {
+                "json_schema" => format.json_schema.map(|json_schema| json_schema.schema),
+                "json_object" => Some(json!({ "type": "object" })),
+                _ => None,
+            });
+
+        Ok(ChatRequest {
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages,
+            options: options.or(self.options.clone()),
+            tools: completion
+                .tools
+                .into_iter()
+                .map(|tool| ToolDefinition {
+                    name: tool.function.name,
+                    description: tool.function.description,
+                    parameters: tool.function.parameters,
+                })
+                .collect(),
+            schema,
+        })
+    }
+
+    /// Logs the provider's warnings and records the answer in the ledger.
+    fn record(&self, response: &AiResponse) {
+        output::print_warnings(response);
+        ledger::record(response, self.pricing.cost(response));
+    }
+}
+
+/// The text of a message and its images, which must be given as data URLs.
+fn content(content: Option<Content>) -> Result<(String, Vec<Image>)> {
+    let parts = match content {
+        None => return Ok((String::new(), Vec::new())),
+        Some(Content::Text(text)) => return Ok((text, Vec::new())),
+        Some(Content::Parts(parts)) => parts,
+    };
+
+    let mut text = Vec::new();
+    let mut images = Vec::new();
+    for part in parts {
+        match part {
+            ContentPart::Text { text: part } => text.push(part),
+            ContentPart::ImageUrl { image_url } => images.push(image(&image_url.url)?),
+            ContentPart::Other => {}
+        }
+    }
+
+    Ok((text.join("\n"), images))
+}
+
+fn image(url: &str) -> Result<Image> {
+    let (mime_type, data) = url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+        .ok_or_else(|| anyhow::anyhow!("Only base64 data URLs are supported for images"))?;
+
+    Ok(Image {
+        mime_type: mime_type.to_string(),
+        data: data.to_string(),
+    })
+}
+
+/// The answer followed by any alternatives, as OpenAI choices.
+fn choices(response: &AiResponse) -> Vec<Choice> {
+    let thinking: String = response
+        .segments
+        .iter()
+        .filter_map(|segment| match segment {
+            providers::Segment::Thinking(text) => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    let answer = Choice {
+        index: 0,
+        message: ResponseMessage {
+            role: "assistant",
+            content: (!response.content.is_empty() || response.tool_calls.is_empty())
+                .then(|| response.content.clone()),
+            reasoning_content: (!thinking.is_empty()).then_some(thinking),
+            tool_calls: response
+                .tool_calls
+                .iter()
+                .map(|call| ResponseToolCall::new(call, None))
+                .collect(),
+        },
+        finish_reason: finish_reason(response),
+    };
+
+    let alternatives = response
+        .alternatives
+        .iter()
+        .enumerate()
+        .map(|(index, text)| Choice {
+            index: index + 1,
+            message: ResponseMessage {
+                role: "assistant",
+                content: Some(text.clone()),
+                reasoning_content: None,
+                tool_calls: Vec::new(),
+            },
+            finish_reason: "stop".to_string(),
+        });
+
+    std::iter::once(answer).chain(alternatives).collect()
+}
+
+/// OpenAI's finish reasons match the normalized ones.
+fn finish_reason(response: &AiResponse) -> String {
+    response
+        .finish_reason
+        .as_ref()
+        .map_or("stop", |reason| reason.as_str())
+        .to_string()
+}
+
+fn sse(event: &impl Serialize) -> String {
+    format!(
+        "data: {}\n\n",
+        serde_json::to_string(event).unwrap_or_default()
+    )
+}
+
+fn json_response(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    let mut response = Response::new(Either::Left(Full::new(Bytes::from(body))));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(
+        status,
+        &json!({ "error": { "message": message, "type": "invalid_request_error" } }),
+    )
+}
+
+/// An OpenAI-style error. Error statuses from the provider are passed on;
+/// timeouts become 504, other provider failures 502, and anything else,
+/// such as an invalid request or an unknown provider, 400.
+fn error_response(error: &anyhow::Error) -> Response<Body> {
+    let provider_error = output::provider_error(error);
+    let status = match provider_error.map(clm::Error::root) {
+        Some(e) if e.status().is_some() => e
+            .status()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap_or(StatusCode::BAD_GATEWAY),
+        Some(clm::Error::Timeout(_)) => StatusCode::GATEWAY_TIMEOUT,
+        Some(clm::Error::Network(_) | clm::Error::Parse(_) | clm::Error::Provider(_)) => {
+            StatusCode::BAD_GATEWAY
+        }
+        // The server is missing a setting, e.g. an API key; the client did
+        // nothing wrong.
+        Some(clm::Error::Config(_) | clm::Error::Missing { .. }) => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        _ => StatusCode::BAD_REQUEST,
+    };
+    json_response(status, &error_body(error))
+}
+
+fn error_body(error: &anyhow::Error) -> Value {
+    let kind = output::provider_error(error).map_or("invalid_request_error", clm::Error::kind);
+    json!({ "error": { "message": format!("{:#}", error), "type": kind } })
+}
@@ -0,0 +1,291 @@
+use crate::output::{self, Delivery};
+use anyhow::Result;
+use clm::providers::{AiProvider, AiResponse, ChatMessage, ChatRequest, ToolCall, ToolDefinition};
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, Write};
+use std::time::Duration;
+
+/// Rounds of tool calls allowed before giving up on a final answer.
+const MAX_ROUNDS: usize = 10;
+
+/// Tool output beyond this many bytes is cut off before it is sent back.
+const MAX_OUTPUT: usize = 100_000;
+
+const TOOL_NAMES: &[&str] = &["current_time", "read_file", "list_directory", "shell"];
+
+/// A built-in tool the model can be allowed to call.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tool {
+    CurrentTime,
+    ReadFile,
+    ListDirectory,
+    /// Runs any command with `sh -c`, so it is never enabled by default.
+    Shell,
+}
+
+impl Tool {
+    fn from_name(name: &str) -> Option<Tool> {
+        match name {
+            "current_time" => Some(Tool::CurrentTime),
+            "read_file" => Some(Tool::ReadFile),
+            "list_directory" => Some(Tool::ListDirectory),
+            "shell" => Some(Tool::Shell),
+            _ => None,
+        }
+    }
+
+    /// Tools that can read or change anything on the machine, and so only
+    /// run once the user allows each call.
+    fn needs_confirmation(self) -> bool {
+        matches!(self, Tool::ReadFile | Tool::Shell)
+    }
+
+    fn definition(self) -> ToolDefinition {
+        let (name, description, parameters) = match self {
+            Tool::CurrentTime => (
+                "current_time",
+                "Get the current local date and time in RFC 3339 format.",
+                json!({ "type": "object", "properties": {} }),
+            ),
+            Tool::ReadFile => (
+                "read_file",
+                "Read a UTF-8 text file from the user's machine.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path of the file" }
+                    },
+                    "required": ["path"]
+                }),
+            ),
+            Tool::ListDirectory => (
+                "list_directory",
+                "List the entries of a directory on the user's machine. Directories end with '/'.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Path of the directory, the current one if omitted"
+                        }
+                    }
+                }),
+            ),
+            Tool::Shell => (
+                "shell",
+                "Run a shell command on the user's machine and return its exit status and output.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "command": { "type": "string", "description": "Command for sh -c" }
+                    },
+                    "required": ["command"]
+                }),
+            ),
+        };
+
+        ToolDefinition {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters,
+        }
+    }
+
+    fn run(self, arguments: &Value) -> Result<String> {
+        let argument = |name: &str| arguments.get(name).and_then(Value::as_str);
+
+        match self {
+            Tool::CurrentTime => Ok(chrono::Local::now().to_rfc3339()),
+            Tool::ReadFile => {
+                let path = argument("path").ok_or_else(|| anyhow::anyhow!("Missing 'path'"))?;
+                std::fs::read_to_string(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path, e))
+            }
+            Tool::ListDirectory => {
+                let path = argument("path").unwrap_or(".");
+                let mut entries = Vec::new();
+                for entry in std::fs::read_dir(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to list {}: {}", path, e))?
+                {
+                    let entry = entry?;
+                    let mut name = entry.file_name().to_string_lossy().into_owned();
+                    if entry.file_type()?.is_dir() {
+                        name.push('/');
+                    }
+                    entries.push(name);
+                }
+                entries.sort();
+                Ok(entries.join("\n"))
+            }
+            Tool::Shell => {
+                let command =
+                    argument("command").ok_or_else(|| anyhow::anyhow!("Missing 'command'"))?;
+                let output = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(std::process::Stdio::null())
+                    .output()
+                    .map_err(|e| anyhow::anyhow!("Failed to run sh: {}", e))?;
+                Ok(format!(
+                    "exit status: {}\nstdout:\n{}\nstderr:\n{}",
+                    output
+                        .status
+                        .code()
+                        .map_or("none".to_string(), |c| c.to_string()),
+                    String::from_utf8_lossy(&output.stdout),
+                    String::from_utf8_lossy(&output.stderr)
+                ))
+            }
+        }
+    }
+}
+
+/// The tools enabled for a query. An empty toolbox sends no tool definitions.
+#[derive(Debug, Clone, Default)]
+pub struct Toolbox {
+    tools: Vec<Tool>,
+}
+
+impl Toolbox {
+    /// Tools enabled with `--tool`.
+    pub fn new(names: &[String]) -> Result<Self> {
+        let mut tools = Vec::new();
+
+        for name in names
+            .iter()
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+        {
+            let tool = Tool::from_name(name).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown tool '{}' (available: {})",
+                    name,
+                    TOOL_NAMES.join(", ")
+                )
+            })?;
+            if !tools.contains(&tool) {
+                tools.push(tool);
+            }
+        }
+
+        Ok(Self { tools })
+    }
+
+    /// Tools enabled with `CLM_TOOLS` or a profile, which apply to every
+    /// query and so may not include `shell`.
+    pub fn configured(names: &[String]) -> Result<Self> {
+        let toolbox = Self::new(names)?;
+        if toolbox.tools.contains(&Tool::Shell) {
+            anyhow::bail!(
+                "The shell tool can only be enabled with --tool, not CLM_TOOLS or a profile"
+            );
+        }
+        Ok(toolbox)
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools.iter().map(|tool| tool.definition()).collect()
+    }
+
+    /// Runs a call and returns what to send back to the model. Failures are
+    /// reported to the model rather than ending the query.
+    fn call(&self, call: &ToolCall) -> String {
+        let tool = Tool::from_name(&call.name).filter(|tool| self.tools.contains(tool));
+
+        let output = match tool {
+            Some(tool) if tool.needs_confirmation() => {
+                confirm(call).and_then(|_| tool.run(&call.arguments))
+            }
+            Some(tool) => tool.run(&call.arguments),
+            None => Err(anyhow::anyhow!("Unknown tool '{}'", call.name)),
+        };
+
+        match output {
+            Ok(output) => truncate(output),
+            Err(e) => format!("Error: {:#}", e),
+        }
+    }
+}
+
+/// Sends the request, runs the tools the model calls and sends their results
+/// back until it answers without calling one. The tool turns are appended to
+/// `request.messages`, and the returned response carries the usage, time and
+/// retries of all rounds.
+pub async fn complete(
+    provider: &(dyn AiProvider + Send + Sync),
+    request: &mut ChatRequest,
+    toolbox: &Toolbox,
+    delivery: Delivery,
+) -> Result<AiResponse> {
+    let mut usage = Default::default();
+    let mut duration = Duration::ZERO;
+    let mut retries = 0;
+
+    for _ in 0..=MAX_ROUNDS {
+        let response = output::send(provider, request, delivery).await?;
+        usage = response.usage.add(&usage);
+        duration += response.duration;
+        retries += response.retries;
+
+        if response.tool_calls.is_empty() {
+            return Ok(AiResponse {
+                usage,
+                duration,
+                retries,
+                ..response
+            });
+        }
+
+        request.messages.push(
+            ChatMessage::tool_calls(response.content, response.tool_calls.clone())
+                .with_reasoning(response.reasoning),
+        );
+        for call in &response.tool_calls {
+            eprintln!("[Tool: {} {}]", call.name, call.arguments);
+            request
+                .messages
+                .push(ChatMessage::tool_result(call, toolbox.call(call)));
+        }
+    }
+
+    anyhow::bail!("No final answer after {} rounds of tool calls", MAX_ROUNDS)
+}
+
+/// Asks the user whether to run a call. The answer is read from the
+/// terminal rather than stdin, which may carry piped input the model was
+/// asked about; without a terminal the call is refused.
+fn confirm(call: &ToolCall) -> Result<()> {
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Not run: '{}' needs the user's confirmation on a terminal",
+                call.name
+            )
+        })?;
+
+    write!(tty, "Allow {}? [y/N] ", call.name)?;
+    let mut answer = String::new();
+    BufReader::new(&tty).read_line(&mut answer)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => anyhow::bail!("Not run: the user declined '{}'", call.name),
+    }
+}
+
+fn truncate(mut output: String) -> String {
+    if output.len() <= MAX_OUTPUT {
+        return output;
+    }
+
+    let mut end = MAX_OUTPUT;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    output.truncate(end);
+    output.push_str("\n[output truncated]");
+    output
+}